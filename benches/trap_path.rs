@@ -0,0 +1,42 @@
+//! Cost of probe hits through the trap handler on the mock OS, with `cargo bench`.
+//! Each round is one pass of a `common::hits` scenario after a first one warmed it up.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::time::Instant;
+
+use common::counting::{allocations, CountingAlloc};
+use common::{hits, setup};
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+const ROUNDS: u32 = 100_000;
+
+fn bench(name: &str, traps: u32, round: fn()) {
+    round();
+    let start = Instant::now();
+    let count = allocations(|| {
+        for _ in 0..ROUNDS {
+            round();
+        }
+    });
+    let elapsed = start.elapsed();
+    println!(
+        "{:<8} {:>8.1} ns/trap {:>8.2} allocations/trap",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(ROUNDS * traps),
+        count as f64 / f64::from(ROUNDS * traps),
+    );
+}
+
+fn main() {
+    let _guard = setup("/bench/trap_path");
+    hits::register("/bench/trap_path");
+    // the hit and the c.ebreak after the slot
+    bench("step", 2, hits::step);
+    bench("emulate", 1, hits::emulate);
+    // the entry, the c.ebreak after the slot and the trampoline
+    bench("call", 3, hits::call);
+}
@@ -0,0 +1,216 @@
+//! Just enough ELF64 to turn a function name into the address to probe.
+//!
+//! Only the section headers, `.symtab`/`.dynsym`, the names looked for in their string tables
+//! and the code before the probed offset are read, through
+//! [`OsInterface::read_file`](crate::OsInterface::read_file).
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::UprobeError;
+use crate::os::os;
+use crate::riscv_insn_decode::get_insn_length;
+
+const EHDR_SIZE: usize = 64;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+/// The most read from the file at once, so that sizes taken from a crafted file
+/// cost no more memory than the file really holds.
+const CHUNK_SIZE: usize = 4096;
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHT_DYNSYM: u32 = 11;
+
+const SHN_UNDEF: u16 = 0;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+struct Section {
+    kind: u32,
+    addr: usize,
+    offset: usize,
+    size: usize,
+    link: usize,
+}
+
+struct Symbol {
+    value: usize,
+    size: usize,
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], at: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+fn read(path: &str, offset: usize, len: usize) -> Result<Vec<u8>, UprobeError> {
+    let mut buf = vec![0; len];
+    os().read_file(path, offset, &mut buf)?;
+    Ok(buf)
+}
+
+/// Call `f` on the entries of `entsize` bytes in the `size` bytes at `offset`, a chunk at a
+/// time, until it finds something.
+fn find_entry<T>(
+    path: &str,
+    offset: usize,
+    size: usize,
+    entsize: usize,
+    mut f: impl FnMut(&[u8]) -> Result<Option<T>, UprobeError>,
+) -> Result<Option<T>, UprobeError> {
+    let end = offset.checked_add(size).ok_or(UprobeError::NotElf)?;
+    let chunk = (CHUNK_SIZE / entsize).max(1) * entsize;
+    let mut at = offset;
+    while end - at >= entsize {
+        let len = chunk.min(end - at);
+        for entry in read(path, at, len)?.chunks_exact(entsize) {
+            if let Some(found) = f(entry)? {
+                return Ok(Some(found));
+            }
+        }
+        at += len;
+    }
+    Ok(None)
+}
+
+/// Whether the code at `offset` of the file starts an instruction `len` bytes further,
+/// walking the instructions in between.
+fn on_boundary(path: &str, offset: usize, len: usize) -> Result<bool, UprobeError> {
+    let mut at = 0;
+    while at < len {
+        // both are even, so every chunk holds the lowest halfword of its instructions
+        let code = read(path, offset + at, (len - at).min(CHUNK_SIZE))?;
+        let mut i = 0;
+        while i < code.len() {
+            match get_insn_length([code[i], code[i + 1]]) {
+                length @ (2 | 4) => i += length,
+                _ => return Err(UprobeError::IllegalInstruction),
+            }
+        }
+        at += i;
+    }
+    Ok(at == len)
+}
+
+/// Split `name+offset` into its parts. The offset may be decimal or `0x` hexadecimal.
+fn parse_spec(spec: &str) -> Result<(&str, usize), UprobeError> {
+    let (name, offset) = match spec.rsplit_once('+') {
+        Some((name, offset)) => {
+            let offset = match offset.strip_prefix("0x").or_else(|| offset.strip_prefix("0X")) {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => offset.parse(),
+            };
+            (name, offset.map_err(|_| UprobeError::BadSymbolOffset)?)
+        }
+        None => (spec, 0),
+    };
+    if name.is_empty() {
+        return Err(UprobeError::SymbolNotFound);
+    }
+    Ok((name, offset))
+}
+
+fn sections(path: &str) -> Result<Vec<Section>, UprobeError> {
+    let ehdr = read(path, 0, EHDR_SIZE)?;
+    if ehdr[..4] != *b"\x7fELF" || ehdr[4] != ELFCLASS64 || ehdr[5] != ELFDATA2LSB || u16_at(&ehdr, 18) != EM_RISCV {
+        return Err(UprobeError::NotElf);
+    }
+    let shoff = u64_at(&ehdr, 40);
+    let shentsize = u16_at(&ehdr, 58) as usize;
+    let shnum = u16_at(&ehdr, 60) as usize;
+    if shentsize != SHDR_SIZE {
+        return Err(UprobeError::NotElf);
+    }
+    let mut sections = Vec::new();
+    find_entry(path, shoff, shentsize * shnum, shentsize, |shdr| {
+        sections.push(Section {
+            kind: u32_at(shdr, 4),
+            addr: u64_at(shdr, 16),
+            offset: u64_at(shdr, 24),
+            size: u64_at(shdr, 32),
+            link: u32_at(shdr, 40) as usize,
+        });
+        Ok(None::<()>)
+    })?;
+    Ok(sections)
+}
+
+fn find_symbol(path: &str, sections: &[Section], kind: u32, name: &str) -> Result<Option<Symbol>, UprobeError> {
+    for symtab in sections.iter().filter(|section| section.kind == kind) {
+        let strtab = sections.get(symtab.link).ok_or(UprobeError::NotElf)?;
+        let found = find_entry(path, symtab.offset, symtab.size, SYM_SIZE, |sym| {
+            let info = sym[4];
+            let shndx = u16_at(sym, 6);
+            if shndx == SHN_UNDEF || !matches!(info & 0xf, STT_FUNC | STT_NOTYPE) {
+                return Ok(None);
+            }
+            // only the name looked for and its terminating nul are read
+            let start = u32_at(sym, 0) as usize;
+            if start >= strtab.size || strtab.size - start <= name.len() {
+                return Ok(None);
+            }
+            let sym_name = read(path, strtab.offset + start, name.len() + 1)?;
+            if sym_name[..name.len()] != *name.as_bytes() || sym_name[name.len()] != 0 {
+                return Ok(None);
+            }
+            Ok(Some(Symbol { value: u64_at(sym, 8), size: u64_at(sym, 16) }))
+        })?;
+        if found.is_some() {
+            return Ok(found);
+        }
+    }
+    Ok(None)
+}
+
+/// Resolve `spec`, written as `name` or `name+offset`, to an address in the ELF file at `path`.
+///
+/// `.symtab` is searched first, then `.dynsym`. The offset has to stay inside the function,
+/// or its section when the size of the function is not known, and land on the first byte of
+/// an instruction, which is checked by walking the code from the start of the function. The
+/// address is the link-time one, so position independent executables are only supported
+/// when loaded at 0.
+pub fn resolve_symbol(path: &str, spec: &str) -> Result<usize, UprobeError> {
+    let (name, offset) = parse_spec(spec)?;
+    let sections = sections(path)?;
+    let symbol = match find_symbol(path, &sections, SHT_SYMTAB, name)? {
+        Some(symbol) => symbol,
+        None => find_symbol(path, &sections, SHT_DYNSYM, name)?.ok_or(UprobeError::SymbolNotFound)?,
+    };
+    let addr = symbol.value.checked_add(offset).ok_or(UprobeError::BadSymbolOffset)?;
+    if offset == 0 {
+        return Ok(addr);
+    }
+    // the code of the function is where its section puts it in the file
+    let section = sections.iter()
+        .filter(|section| section.kind != SHT_NOBITS)
+        .find(|section| section.addr <= symbol.value && symbol.value - section.addr < section.size)
+        .ok_or(UprobeError::BadSymbolOffset)?;
+    let start = symbol.value - section.addr;
+    let size = match symbol.size {
+        0 => section.size - start,
+        size => size.min(section.size - start),
+    };
+    if offset % 2 != 0 || offset >= size || section.offset.checked_add(section.size).is_none() {
+        return Err(UprobeError::BadSymbolOffset);
+    }
+    if !on_boundary(path, section.offset + start, offset)? {
+        return Err(UprobeError::BadSymbolOffset);
+    }
+    Ok(addr)
+}
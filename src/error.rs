@@ -0,0 +1,62 @@
+use core::fmt;
+
+/// Why a uprobe could not be registered, armed or handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UprobeError {
+    /// The probed instruction can not be executed out of line.
+    IllegalInstruction,
+    /// Reading or writing user memory failed.
+    CopyFault,
+    /// The OS could not give us a page for the slot or the return breakpoint.
+    NoFreePage,
+    /// There are no probes for the executable.
+    UnknownPath,
+    /// A probe of another type is already registered at this address.
+    ProbeTypeMismatch,
+    /// There is no probe registered at this address.
+    NotRegistered,
+    /// The probe, as it was asked for, is not supported.
+    Unsupported,
+    /// The kernel's `OsInterface` keeps the default of a hook this needs.
+    MissingOsHook,
+    /// The entry data asked for by a return probe is larger than `UPROBE_MAX_DATA_SIZE`.
+    DataTooLarge,
+    /// A thread returned through a return probe trampoline without having entered the function.
+    NoPendingReturn,
+    /// The executable could not be read.
+    FileRead,
+    /// The executable is not a 64-bit little endian RISC-V ELF file.
+    NotElf,
+    /// No function with this name in `.symtab` or `.dynsym`.
+    SymbolNotFound,
+    /// The offset after `+` is malformed, not on an instruction or past the end of the function.
+    BadSymbolOffset,
+    /// The probed address already holds `ebreak` or `c.ebreak`, e.g. a breakpoint of a debugger.
+    ExistingBreakpoint,
+    /// The probed address of a process holds another instruction than the one the probe was made for.
+    InstructionMismatch,
+}
+
+impl fmt::Display for UprobeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            UprobeError::IllegalInstruction => "instruction can not be probed",
+            UprobeError::CopyFault => "failed to access user memory",
+            UprobeError::NoFreePage => "no free page for uprobe slots",
+            UprobeError::UnknownPath => "no uprobes for this executable",
+            UprobeError::ProbeTypeMismatch => "a uprobe of another type is registered at this address",
+            UprobeError::NotRegistered => "no uprobe registered at this address",
+            UprobeError::Unsupported => "probe not supported",
+            UprobeError::MissingOsHook => "OS hook not implemented",
+            UprobeError::DataTooLarge => "return probe entry data is too large",
+            UprobeError::NoPendingReturn => "no pending return for this thread",
+            UprobeError::FileRead => "failed to read the executable",
+            UprobeError::NotElf => "not a RISC-V ELF64 file",
+            UprobeError::SymbolNotFound => "symbol not found",
+            UprobeError::BadSymbolOffset => "offset is outside of the function",
+            UprobeError::ExistingBreakpoint => "address already holds a breakpoint",
+            UprobeError::InstructionMismatch => "address holds another instruction than the probed one",
+        };
+        f.write_str(msg)
+    }
+}
@@ -0,0 +1,54 @@
+//! Call durations collected by probes from [`uprobe_register_latency`](crate::uprobe_register_latency).
+
+/// Bucket `i` counts the durations `d` with `2^i <= d < 2^(i+1)`, bucket 0 also counts 0.
+pub const LATENCY_BUCKETS: usize = 64;
+
+/// A log2 histogram of call durations, in the unit of [`OsInterface::current_time`](crate::OsInterface::current_time).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub count: u64,
+    /// Zero until the first call returned.
+    pub min: u64,
+    pub max: u64,
+    /// Saturates instead of wrapping.
+    pub sum: u64,
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub const fn new() -> Self {
+        Self {
+            count: 0,
+            min: 0,
+            max: 0,
+            sum: 0,
+            buckets: [0; LATENCY_BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, duration: u64) {
+        self.min = if self.count == 0 { duration } else { self.min.min(duration) };
+        self.max = self.max.max(duration);
+        self.count += 1;
+        self.sum = self.sum.saturating_add(duration);
+        self.buckets[Self::bucket_of(duration)] += 1;
+    }
+
+    /// Which of [`buckets`](Self::buckets) `duration` falls into.
+    pub fn bucket_of(duration: u64) -> usize {
+        match duration {
+            0 => 0,
+            _ => 63 - duration.leading_zeros() as usize,
+        }
+    }
+
+    pub fn mean(&self) -> Option<u64> {
+        self.sum.checked_div(self.count)
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
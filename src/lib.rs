@@ -1,35 +1,42 @@
 #![no_std]
-#![feature(naked_functions)]
-#![feature(asm)]
-#![feature(unsafe_block_in_unsafe_fn)]
 #[macro_export]
 
 #[macro_use]
 extern crate log;
 extern crate alloc;
-
-use alloc::string::String;
-extern "C" {
-    fn get_new_page(addr: usize, len: usize) -> usize;
-    fn set_writeable(addr: usize);
-    fn get_exec_path() -> String;
-    fn os_copy_from_user(usr_addr: usize, kern_buf: *mut u8, len: usize) -> i32;
-    fn os_copy_to_user(usr_addr: usize, kern_buf: *const u8, len: usize) -> i32;
-}
+#[cfg(feature = "std")]
+extern crate std;
 
 // mod kprobes;
+mod elf;
+mod error;
+mod latency;
+mod os;
 mod riscv_insn_decode;
+mod riscv_insn_emulate;
 mod uprobes;
+mod xol;
 mod probes;
+#[cfg(feature = "std")]
+pub mod mock;
 
 //use alloc::sync::Arc;
 // pub use kprobes::kprobes_trap_handler;
-pub use uprobes::uprobes_trap_handler;
+pub use uprobes::{uprobes_trap_handler, UprobeTrapResult};
 //use spin::Mutex;
 //use trapframe::TrapFrame;
 pub use probes::ProbeType;
+pub use probes::ForkPolicy;
 pub use probes::ProbePlace;
-pub use uprobes::{uprobes_init,uprobe_register};
+pub use error::UprobeError;
+pub use latency::{LatencyHistogram, LATENCY_BUCKETS};
+pub use elf::resolve_symbol;
+pub use os::{FileId, OsInterface, uprobes_os_init};
+pub use uprobes::{uprobes_init,uprobes_exit,uprobes_thread_exit,uprobes_fork,uprobe_register,uprobe_register_symbol,uprobe_register_with_data,uprobe_unregister,uprobe_register_async,uprobe_register_async_drop,uprobe_register_latency,uretprobe_register};
+pub use uprobes::{AsyncEvent, AsyncFuture, AsyncHandler, PollAbi};
+pub use uprobes::{ProbeId, UprobeHandler, UprobePostHandler};
+pub use uprobes::{UretprobeEntryHandler, UretprobeHandler, UretprobeInstance, UPROBE_MAX_DATA_SIZE};
+pub use trap_context_riscv::TrapContext;
 // pub use kprobes::ProbeType;
 
 // pub fn kprobe_register(addr: usize, handler: Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>, post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>>, probe_type: ProbeType) -> isize {
@@ -40,13 +47,3 @@ pub use uprobes::{uprobes_init,uprobe_register};
 //     kprobes::KPROBES.unregister_kprobe(addr)
 // }
 
-
-// #[cfg(rCore-Tutorial)]
-// pub fn uprobe_register(path: String, addr: usize, handler: Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>, post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapFrame) + Send>>>, probe_type: ProbeType) -> isize {
-//     uprobes::UPROBES.register_uprobe()
-// }
-
-// #[cfg(rCore-Tutorial)]
-// pub fn uprobe_unregister(path: String, addr: usize) -> isize {
-//     todo!()
-// }
\ No newline at end of file
@@ -0,0 +1,379 @@
+//! A host-side [`OsInterface`] backed by a simulated user address space.
+//!
+//! Enabled with the `std` feature. It models one sparse set of user pages with
+//! read/write/execute permissions per process and hands out XOL pages from a reserved
+//! region, so the whole probe flow can be driven from ordinary tests.
+
+use std::boxed::Box;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::string::String;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use crate::error::UprobeError;
+use crate::os::{FileId, OsInterface};
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub const PERM_R: u8 = 0b001;
+pub const PERM_W: u8 = 0b010;
+pub const PERM_X: u8 = 0b100;
+
+/// Where `alloc_xol_page` starts handing out pages.
+pub const XOL_BASE: usize = 0x3f_0000_0000;
+
+std::thread_local! {
+    /// Each host thread plays one user thread, so that tests can hit probes from several at once.
+    static THREAD_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Clone)]
+struct Page {
+    data: Box<[u8; PAGE_SIZE]>,
+    perm: u8,
+}
+
+struct MockState {
+    /// The address space of each process, by pid.
+    spaces: BTreeMap<usize, BTreeMap<usize, Page>>,
+    pid: usize,
+    next_xol: usize,
+    /// The executable each process runs, by pid.
+    exec_paths: BTreeMap<usize, String>,
+    /// Symbolic links, to their target.
+    links: BTreeMap<String, String>,
+    /// Inode numbers handed out to files, by path. Kept by `reset` like the probes
+    /// registered by earlier tests are, so that a file is never mistaken for another.
+    inodes: BTreeMap<String, u64>,
+    icache_flushes: usize,
+    files: BTreeMap<String, Vec<u8>>,
+    time: u64,
+    /// Whether `copy_to_process` and `copy_from_process` are implemented.
+    remote_hooks: bool,
+}
+
+pub struct MockOs {
+    state: Mutex<MockState>,
+}
+
+fn page_of(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn pages_of(addr: usize, len: usize) -> impl Iterator<Item = usize> {
+    let end = addr + len.max(1);
+    (page_of(addr)..end).step_by(PAGE_SIZE)
+}
+
+impl MockState {
+    fn pages(&mut self) -> &mut BTreeMap<usize, Page> {
+        self.space(self.pid)
+    }
+
+    fn space(&mut self, pid: usize) -> &mut BTreeMap<usize, Page> {
+        self.spaces.entry(pid).or_default()
+    }
+
+    fn copy_out(&mut self, pid: usize, addr: usize, buf: &mut [u8], need: u8) -> Result<(), UprobeError> {
+        let pages = self.space(pid);
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let va = addr + i;
+            match pages.get(&page_of(va)) {
+                Some(page) if page.perm & need == need => *byte = page.data[va % PAGE_SIZE],
+                _ => return Err(UprobeError::CopyFault),
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow `path` through symbolic links and number the file it ends at.
+    fn file_id(&mut self, path: &str) -> FileId {
+        let mut path = path;
+        while let Some(target) = self.links.get(path) {
+            path = target;
+        }
+        let next = self.inodes.len() as u64 + 1;
+        let ino = *self.inodes.entry(String::from(path)).or_insert(next);
+        FileId { dev: 0, ino }
+    }
+
+    fn copy_in(&mut self, pid: usize, addr: usize, buf: &[u8], need: u8) -> Result<(), UprobeError> {
+        let pages = self.space(pid);
+        // check the whole range first so that a fault leaves memory untouched
+        for va in pages_of(addr, buf.len()) {
+            match pages.get(&va) {
+                Some(page) if page.perm & need == need => {}
+                _ => return Err(UprobeError::CopyFault),
+            }
+        }
+        for (i, byte) in buf.iter().enumerate() {
+            let va = addr + i;
+            pages.get_mut(&page_of(va)).unwrap().data[va % PAGE_SIZE] = *byte;
+        }
+        Ok(())
+    }
+}
+
+impl MockOs {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(MockState {
+                spaces: BTreeMap::new(),
+                pid: 0,
+                next_xol: XOL_BASE,
+                exec_paths: BTreeMap::new(),
+                links: BTreeMap::new(),
+                inodes: BTreeMap::new(),
+                icache_flushes: 0,
+                files: BTreeMap::new(),
+                time: 0,
+                remote_hooks: true,
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Forget every process, page and counter, leaving only an empty process 0.
+    pub fn reset(&self) {
+        let mut state = self.state();
+        state.spaces.clear();
+        state.pid = 0;
+        state.next_xol = XOL_BASE;
+        state.exec_paths.clear();
+        state.links.clear();
+        THREAD_ID.with(|tid| tid.set(0));
+        state.icache_flushes = 0;
+        state.files.clear();
+        state.time = 0;
+        state.remote_hooks = true;
+    }
+
+    /// Map zeroed pages covering `addr..addr + len` with `perm`.
+    pub fn map(&self, addr: usize, len: usize, perm: u8) {
+        let mut state = self.state();
+        for va in pages_of(addr, len) {
+            state.pages().insert(va, Page { data: Box::new([0; PAGE_SIZE]), perm });
+        }
+    }
+
+    pub fn unmap(&self, addr: usize, len: usize) {
+        let mut state = self.state();
+        for va in pages_of(addr, len) {
+            state.pages().remove(&va);
+        }
+    }
+
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        self.state().pages().contains_key(&page_of(addr))
+    }
+
+    pub fn perm(&self, addr: usize) -> Option<u8> {
+        self.state().pages().get(&page_of(addr)).map(|page| page.perm)
+    }
+
+    /// Write like a loader would, ignoring permissions. Panics on unmapped memory.
+    pub fn write(&self, addr: usize, bytes: &[u8]) {
+        let mut state = self.state();
+        let pid = state.pid;
+        state.copy_in(pid, addr, bytes, 0).expect("mock: write to unmapped memory");
+    }
+
+    /// Read ignoring permissions. Panics on unmapped memory.
+    pub fn read(&self, addr: usize, len: usize) -> Vec<u8> {
+        let mut buf = std::vec![0; len];
+        let mut state = self.state();
+        let pid = state.pid;
+        state.copy_out(pid, addr, &mut buf, 0).expect("mock: read from unmapped memory");
+        buf
+    }
+
+    /// Make the current process run `path`, like its exec does.
+    pub fn set_exec_path(&self, path: &str) {
+        let mut state = self.state();
+        let pid = state.pid;
+        state.exec_paths.insert(pid, String::from(path));
+    }
+
+    /// Switch to the address space of process `pid`, which is empty if it was never used.
+    pub fn set_pid(&self, pid: usize) {
+        self.state().pid = pid;
+    }
+
+    /// Give `child` a copy of the address space of `parent`, like `fork` does.
+    pub fn fork(&self, parent: usize, child: usize) {
+        let mut state = self.state();
+        let pages = state.space(parent).clone();
+        state.spaces.insert(child, pages);
+        if let Some(path) = state.exec_paths.get(&parent).cloned() {
+            state.exec_paths.insert(child, path);
+        }
+    }
+
+    /// Drop the address space of process `pid`, like its exit does.
+    pub fn remove_process(&self, pid: usize) {
+        let mut state = self.state();
+        state.spaces.remove(&pid);
+        state.exec_paths.remove(&pid);
+    }
+
+    /// Set the thread id the calling host thread reports.
+    pub fn set_thread_id(&self, tid: usize) {
+        THREAD_ID.with(|id| id.set(tid));
+    }
+
+    /// Make `link` a symbolic link to `target`.
+    pub fn add_link(&self, link: &str, target: &str) {
+        self.state().links.insert(String::from(link), String::from(target));
+    }
+
+    /// Make `bytes` readable through `read_file` as the file at `path`.
+    pub fn add_file(&self, path: &str, bytes: &[u8]) {
+        self.state().files.insert(String::from(path), bytes.to_vec());
+    }
+
+    /// Move the clock read by `current_time` forward.
+    pub fn advance_time(&self, ticks: u64) {
+        self.state().time += ticks;
+    }
+
+    /// Let `copy_to_process` and `copy_from_process` fail with `MissingOsHook` as their
+    /// defaults do, like a kernel that can not reach into other processes.
+    pub fn set_remote_hooks(&self, enabled: bool) {
+        self.state().remote_hooks = enabled;
+    }
+
+    /// Number of XOL pages currently mapped, over all processes.
+    pub fn xol_pages(&self) -> usize {
+        self.state().spaces.values().map(|pages| pages.range(XOL_BASE..).count()).sum()
+    }
+
+    pub fn icache_flushes(&self) -> usize {
+        self.state().icache_flushes
+    }
+}
+
+impl Default for MockOs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OsInterface for MockOs {
+    fn copy_from_user(&self, usr_addr: usize, buf: &mut [u8]) -> Result<(), UprobeError> {
+        let mut state = self.state();
+        let pid = state.pid;
+        state.copy_out(pid, usr_addr, buf, PERM_R)
+    }
+
+    fn copy_to_user(&self, usr_addr: usize, buf: &[u8]) -> Result<(), UprobeError> {
+        let mut state = self.state();
+        let pid = state.pid;
+        state.copy_in(pid, usr_addr, buf, PERM_W)
+    }
+
+    fn alloc_xol_page(&self, _addr: usize, len: usize) -> Result<usize, UprobeError> {
+        let mut state = self.state();
+        let base = state.next_xol;
+        let count = len.div_ceil(PAGE_SIZE);
+        for i in 0..count {
+            // execute-only, written through `copy_to_xol`
+            state.pages().insert(base + i * PAGE_SIZE, Page { data: Box::new([0; PAGE_SIZE]), perm: PERM_X });
+        }
+        // leave a guard page between allocations
+        state.next_xol = base + (count + 1) * PAGE_SIZE;
+        Ok(base)
+    }
+
+    fn free_xol_page(&self, addr: usize, len: usize) {
+        let mut state = self.state();
+        for va in pages_of(addr, len) {
+            state.pages().remove(&va);
+        }
+    }
+
+    fn copy_to_xol(&self, usr_addr: usize, buf: &[u8]) -> Result<(), UprobeError> {
+        let mut state = self.state();
+        let pid = state.pid;
+        state.copy_in(pid, usr_addr, buf, PERM_X)?;
+        state.icache_flushes += 1;
+        Ok(())
+    }
+
+    fn set_writeable(&self, addr: usize) -> Result<(), UprobeError> {
+        match self.state().pages().get_mut(&page_of(addr)) {
+            Some(page) => {
+                page.perm |= PERM_W;
+                Ok(())
+            }
+            None => Err(UprobeError::CopyFault),
+        }
+    }
+
+    fn exec_path(&self) -> String {
+        let state = self.state();
+        state.exec_paths.get(&state.pid).cloned().unwrap_or_default()
+    }
+
+    fn current_pid(&self) -> usize {
+        self.state().pid
+    }
+
+    fn current_thread_id(&self) -> usize {
+        THREAD_ID.with(|tid| tid.get())
+    }
+
+    fn flush_icache(&self) {
+        self.state().icache_flushes += 1;
+    }
+
+    fn copy_to_process(&self, pid: usize, usr_addr: usize, buf: &[u8]) -> Result<(), UprobeError> {
+        let mut state = self.state();
+        if !state.remote_hooks {
+            return Err(UprobeError::MissingOsHook);
+        }
+        if !state.spaces.contains_key(&pid) {
+            return Err(UprobeError::CopyFault);
+        }
+        state.copy_in(pid, usr_addr, buf, 0)
+    }
+
+    fn copy_from_process(&self, pid: usize, usr_addr: usize, buf: &mut [u8]) -> Result<(), UprobeError> {
+        let mut state = self.state();
+        if !state.remote_hooks {
+            return Err(UprobeError::MissingOsHook);
+        }
+        if !state.spaces.contains_key(&pid) {
+            return Err(UprobeError::CopyFault);
+        }
+        state.copy_out(pid, usr_addr, buf, 0)
+    }
+
+    fn file_id(&self, path: &str) -> Result<FileId, UprobeError> {
+        Ok(self.state().file_id(path))
+    }
+
+    fn processes_running(&self, file: FileId) -> Vec<usize> {
+        let mut state = self.state();
+        let processes: Vec<(usize, String)> = state.exec_paths.iter().map(|(&pid, path)| (pid, path.clone())).collect();
+        processes.into_iter().filter(|(_, path)| state.file_id(path) == file).map(|(pid, _)| pid).collect()
+    }
+
+    fn current_time(&self) -> Result<u64, UprobeError> {
+        Ok(self.state().time)
+    }
+
+    fn read_file(&self, path: &str, offset: usize, buf: &mut [u8]) -> Result<(), UprobeError> {
+        let state = self.state();
+        let file = state.files.get(path).ok_or(UprobeError::FileRead)?;
+        let bytes = offset
+            .checked_add(buf.len())
+            .and_then(|end| file.get(offset..end))
+            .ok_or(UprobeError::FileRead)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+use crate::error::UprobeError;
+
+/// Tells executable files apart whatever path they are reached by, e.g. the device and inode
+/// numbers of the file, or two halves of a digest of its build id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+/// What `ruprobes` needs from the kernel it runs in.
+///
+/// Every address is a user virtual address of the current process.
+/// Register an implementation once with [`uprobes_os_init`] before any other call into the crate.
+pub trait OsInterface: Send + Sync {
+    /// Copy `buf.len()` bytes at `usr_addr` into `buf`.
+    fn copy_from_user(&self, usr_addr: usize, buf: &mut [u8]) -> Result<(), UprobeError>;
+
+    /// Copy `buf` to `usr_addr`. Must also work on executable pages made writable by `set_writeable`.
+    fn copy_to_user(&self, usr_addr: usize, buf: &[u8]) -> Result<(), UprobeError>;
+
+    /// Map at least `len` bytes of executable user memory, preferably near `addr`,
+    /// for out-of-line execution (XOL) and return its address. The user only needs to
+    /// execute it when `copy_to_xol` is overridden, otherwise it has to be writable as well.
+    fn alloc_xol_page(&self, addr: usize, len: usize) -> Result<usize, UprobeError>;
+
+    /// Unmap memory returned by `alloc_xol_page`.
+    fn free_xol_page(&self, addr: usize, len: usize);
+
+    /// Write `buf` to `usr_addr` in memory returned by `alloc_xol_page`, whatever the user may
+    /// do with it, and make instruction fetches see it. Goes through `copy_to_user` and
+    /// `flush_icache` unless overridden, for kernels mapping the area execute-only.
+    fn copy_to_xol(&self, usr_addr: usize, buf: &[u8]) -> Result<(), UprobeError> {
+        self.copy_to_user(usr_addr, buf)?;
+        self.flush_icache();
+        Ok(())
+    }
+
+    /// Make the page containing `addr` writable so that the probed instruction can be patched.
+    fn set_writeable(&self, addr: usize) -> Result<(), UprobeError>;
+
+    /// Path of the executable the current process runs.
+    fn exec_path(&self) -> String;
+
+    /// Identity of the file at `path`, after following symbolic links. Probes are kept by file,
+    /// so that every process running it gets them, whatever path it was started by.
+    /// Tells files apart by their path only unless overridden.
+    fn file_id(&self, path: &str) -> Result<FileId, UprobeError> {
+        Ok(path_id(path))
+    }
+
+    /// Identity of the executable the current process runs, as [`file_id`](Self::file_id) gives it.
+    fn exec_file_id(&self) -> Result<FileId, UprobeError> {
+        self.file_id(&self.exec_path())
+    }
+
+    /// Id of the current process.
+    fn current_pid(&self) -> usize;
+
+    /// Id of the current thread, unique among the live threads of the process.
+    /// Return probes keep the pending return addresses of each thread apart with it.
+    fn current_thread_id(&self) -> usize;
+
+    /// Write `buf` to `usr_addr` of process `pid`, also to read-only text, and make instruction
+    /// fetches of that process see it. Only needed to strip probes from forked children and to
+    /// probe processes other than the current one, which are left alone without it.
+    fn copy_to_process(&self, _pid: usize, _usr_addr: usize, _buf: &[u8]) -> Result<(), UprobeError> {
+        Err(UprobeError::MissingOsHook)
+    }
+
+    /// Copy `buf.len()` bytes at `usr_addr` of process `pid` into `buf`.
+    /// Only needed to probe processes other than the current one, which are left alone without it.
+    fn copy_from_process(&self, _pid: usize, _usr_addr: usize, _buf: &mut [u8]) -> Result<(), UprobeError> {
+        Err(UprobeError::MissingOsHook)
+    }
+
+    /// Ids of the live processes running the executable `file`. Probes registered for it
+    /// are armed in all of them right away; without this hook only the current process is,
+    /// and the others at their next exec.
+    ///
+    /// A probe is at the same virtual address in every process, so leave out processes that
+    /// map `file` at another base than the registering one, e.g. position independent
+    /// executables loaded elsewhere. One left in fails the registration with
+    /// `InstructionMismatch`, or `CopyFault`, as the probed instruction is not found there.
+    fn processes_running(&self, _file: FileId) -> Vec<usize> {
+        Vec::new()
+    }
+
+    /// Make instruction fetches see what was written with `copy_to_user`, e.g. `fence.i`.
+    fn flush_icache(&self);
+
+    /// A monotonic clock, e.g. `time` CSR ticks or nanoseconds. Durations recorded by
+    /// latency probes are in its unit. Reads `rdtime` on riscv64 unless overridden.
+    fn current_time(&self) -> Result<u64, UprobeError> {
+        rdtime()
+    }
+
+    /// Fill `buf` with the bytes at `offset` of the file at `path`, failing on a short read.
+    /// Only needed to register probes by symbol name.
+    fn read_file(&self, _path: &str, _offset: usize, _buf: &mut [u8]) -> Result<(), UprobeError> {
+        Err(UprobeError::MissingOsHook)
+    }
+}
+
+/// FNV-1a of the path, for kernels without a notion of file identity.
+fn path_id(path: &str) -> FileId {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in path.bytes() {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+    }
+    FileId { dev: 0, ino: hash }
+}
+
+#[cfg(target_arch = "riscv64")]
+fn rdtime() -> Result<u64, UprobeError> {
+    let time: u64;
+    unsafe { core::arch::asm!("rdtime {}", out(reg) time) };
+    Ok(time)
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn rdtime() -> Result<u64, UprobeError> {
+    Err(UprobeError::MissingOsHook)
+}
+
+static OS: Once<&'static dyn OsInterface> = Once::new();
+
+/// Register the kernel's [`OsInterface`]. Only the first call has an effect.
+pub fn uprobes_os_init(os: &'static dyn OsInterface) {
+    OS.call_once(|| os);
+}
+
+pub(crate) fn os() -> &'static dyn OsInterface {
+    *OS.wait().expect("uprobes: OsInterface is not registered, call uprobes_os_init first")
+}
@@ -5,90 +5,28 @@
 // use spin::Mutex;
 // use alloc::sync::Arc;
 // use alloc::vec::Vec;
-use core::convert::TryInto;
-use core::slice::from_raw_parts;
-use crate::os_copy_from_user;
 //, from_raw_parts_mut};
-use crate::riscv_insn_decode::get_insn_length;
 //use trapframe::UserContext;
 //use super::kprobes::kprobe_register;
 //use super::uprobes::uprobe_register;
 
-pub unsafe fn get_sp(addr: usize) -> Option<usize>{
-
-
-    let mut slot_copy:[u8;4] = [0,0,0,0];
-    os_copy_from_user(addr, &mut (slot_copy[0]), 4);
-    let slot = &slot_copy;
-
-
-    //let slot: &[u8] = unsafe { from_raw_parts(addr as *const u8, 4) };
-    let mut addisp: usize= 0;
-    match get_insn_length(addr) {
-        4 => {
-            // normal instruction
-            let inst = u32::from_le_bytes(slot[..4].try_into().unwrap());
-            if inst & 0b00000000000011111111111111111111 == 0b00000000000000010000000100010011 {
-                // addi sp, sp, imm
-                addisp = sext(((inst >> 20) & 0b111111111111) as isize, 12) as usize;
-                debug!("uprobes: hook on addi sp, sp, {}", addisp);
-            } else {
-                warn!("uprobes: target instruction is not addi sp, sp, imm");
-                return None;
-            }
-        }
-        2 => {
-            // compressed instruction
-            let inst = u16::from_le_bytes(slot[..2].try_into().unwrap());
-            if inst & 0b1110111110000011 == 0b0110000100000001 {
-                // c.addi16sp imm
-                addisp = sext(
-                    ((((inst >> 12) & 0b1) << 9)
-                        + (((inst >> 6) & 0b1) << 4)
-                        + (((inst >> 5) & 0b1) << 6)
-                        + (((inst >> 3) & 0b11) << 7)
-                        + (((inst >> 2) & 0b1) << 5)) as isize,
-                    10,
-                ) as usize;
-                debug!("uprobes: hook on c.addi16sp {}", addisp as isize);
-            } else if inst & 0b1110111110000011 == 0b0000000100000001 {
-                // c.addi sp, imm
-                addisp = sext(
-                    ((((inst >> 12) & 0b1) << 5) + (((inst >> 2) & 0b11111) << 0)) as isize,
-                    6,
-                ) as usize;
-                debug!("uprobes: hook on c.addi sp, {}", addisp as isize);
-            } else if  inst & 0b1110000000000011 == 0 {
-                // c.addi4spn
-                addisp = sext(((((inst >> 11) & 0b111) << 3)
-                    + (((inst >> 7) & 0b1111) << 5)
-                    + (((inst >> 6) & 0b1) << 1)
-                    + (((inst >> 5) & 0b1) << 2)) as isize,
-                    10
-                ) as usize;
-                // println!("kprobes: hook on c.addi4spn, {}", addisp);
-            } else {
-                error!("uprobes: target instruction is not c.addi sp, imm or c.addi16sp imm or c.addi4spn imm");
-                return None;
-            }
-        }
-        _ => return None
-    };
-    Some(addisp)
-}
-
-fn sext(x: isize, size: usize) -> isize {
-    let shift = core::mem::size_of::<isize>() * 8 - size;
-    (x << shift) >> shift
-}
-
 #[derive(Clone, Debug)]
 pub enum ProbePlace {
     Kernel(ProbeType),
     User(ProbeType),
 }
 
-#[derive(Clone, Debug)]
+/// What a forked child gets of a probe, see [`uprobes_fork`](crate::uprobes_fork).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForkPolicy {
+    /// The breakpoint stays in the child, and calls in flight keep their return handlers.
+    #[default]
+    Inherit,
+    /// The original instruction is put back in the child, which runs unprobed.
+    Strip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ProbeType {
     Insn,
     SyncFunc,
@@ -1,26 +1,7 @@
 use riscv_decode::{decode, Instruction, instruction_length};
 
-use crate::os_copy_from_user;
+use crate::riscv_insn_emulate::{c_decode_emulated, decode_emulated, EmulatedInsn};
 
-// extern "C" {
-//     fn os_copy_from_user(usr_addr: usize, kern_buf: *mut u8, len: usize) -> i32;
-//     fn os_copy_to_user(usr_addr: usize, kern_buf: *const u8, len: usize) -> i32;
-// }
-
-
-/// be careful about endianess
-fn arr_to_u32_as_it_is(array: &[u8; 4]) -> u32 {
-    ((array[0] as u32) << 24) +
-    ((array[1] as u32) << 16) +
-    ((array[2] as u32) <<  8) +
-    ((array[3] as u32) <<  0)
-}
-
-/// be careful about endianess
-fn arr_to_u16_as_it_is(array: &[u8; 2]) -> u16 {
-    ((array[0] as u16) << 8) +
-    ((array[1] as u16) << 0) 
-}
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u8)]
@@ -67,30 +48,35 @@ pub enum Opcode {
 pub enum InsnStatus {
     Illegal,
     Legal,
+    /// Depends on the pc, has to be emulated instead of executed out of line.
+    Emulate(EmulatedInsn),
 }
 
-pub unsafe fn insn_decode(addr: usize) -> InsnStatus{
+/// Check the instruction in `insn`, of which only the first two bytes are used when it is compressed.
+pub fn insn_decode(insn: [u8; 4]) -> InsnStatus{
     //IF YOU CHANGE ENDIAN OF THE MACHINE, THE FOLLOWING CODE SHOULD BE CHANGED.
-    let mut addr_32bit_array:[u8;4]=[0,0,0,0];
-    os_copy_from_user(addr, &mut(addr_32bit_array[0]), 32/8);//czy MIGHT BE PROBLEMATIC DUE TO ENDIAN AND ALIGNMENT
-    let addr_32:[u32;1]=[arr_to_u32_as_it_is(&addr_32bit_array)];
+    let addr_32bit_array = insn;
+    let addr_32:[u32;1]=[u32::from_le_bytes(addr_32bit_array)];
     //let addr_32 = unsafe{core::slice::from_raw_parts(addr as *const u32, 1)}; 
     if addr_32[0] & 0b11 != 0b11{
         let mut addr_16bit_array:[u8;2]=[0,0];
-        os_copy_from_user(addr, &mut(addr_16bit_array[0]), 16/8);//czy MIGHT BE PROBLEMATIC DUE TO ENDIAN AND ALIGNMENT
-        let addr_16:[u16;1]=[arr_to_u16_as_it_is(&addr_16bit_array)];
+        addr_16bit_array.copy_from_slice(&addr_32bit_array[..2]);
+        let addr_16:[u16;1]=[u16::from_le_bytes(addr_16bit_array)];
         //let addr_16 = unsafe{core::slice::from_raw_parts(addr as *const u16, 1)};
+        if let Some(insn) = c_decode_emulated(addr_16[0]) {
+            return InsnStatus::Emulate(insn);
+        }
         match c_decode(addr_16[0]){
-            Opcode::CJ => return InsnStatus::Illegal,
             Opcode::CJR => return InsnStatus::Illegal,
             Opcode::CJALR => return InsnStatus::Illegal,
-            Opcode::CBEQZ => return InsnStatus::Illegal,
-            Opcode::CBNEZ => return InsnStatus::Illegal,
             Opcode::CEBREAK => return InsnStatus::Illegal,
             Opcode::NOTFOUND => {},
             _ => return InsnStatus::Legal,
         }
     }
+    if let Some(insn) = decode_emulated(addr_32[0]) {
+        return InsnStatus::Emulate(insn);
+    }
     match decode(addr_32[0]){
         Ok(Instruction::Ecall) => InsnStatus::Illegal,
         Ok(Instruction::Ebreak) => InsnStatus::Illegal,
@@ -107,13 +93,6 @@ pub unsafe fn insn_decode(addr: usize) -> InsnStatus{
         Ok(Instruction::Csrrci(_)) => InsnStatus::Illegal,
         Ok(Instruction::Fence(_)) => InsnStatus::Illegal,
         Ok(Instruction::FenceI) => InsnStatus::Illegal,
-        Ok(Instruction::Auipc(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Beq(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Bne(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Bltu(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Bge(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Bltu(_)) => InsnStatus::Illegal,
-        Ok(Instruction::Bgeu(_)) => InsnStatus::Illegal,
         Err(_) => InsnStatus::Illegal,
         _ => InsnStatus::Legal,
     }
@@ -189,13 +168,8 @@ pub fn c_decode(base: u16) -> Opcode {
     }
 }
 
-pub fn get_insn_length(addr: usize) -> usize{
-    let mut buffer:[u8;2] = [0,0];
-    let buffer_pointer = &mut buffer[0];
-    unsafe {
-        crate::os_copy_from_user(addr, buffer_pointer, 2);
-    }
-    //let addr = unsafe{core::slice::from_raw_parts(addr as *const u16, 1)};
-    let addr = unsafe{core::slice::from_raw_parts(&buffer as *const u8 as *const u16, 1)};
-    instruction_length(addr[0])
+/// Length of the instruction starting with the two bytes in `insn`.
+pub fn get_insn_length(insn: [u8; 2]) -> usize{
+    // the bytes are not necessarily u16 aligned in user memory
+    instruction_length(u16::from_le_bytes(insn))
 }
\ No newline at end of file
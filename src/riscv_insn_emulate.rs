@@ -0,0 +1,141 @@
+use trap_context_riscv::TrapContext;
+
+use crate::riscv_insn_decode::{c_decode, Opcode};
+
+/// Instructions which read or write the pc, so their result would be wrong if they were
+/// executed from the out-of-line slot. We compute them on the trap context instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmulatedInsn {
+    Auipc { rd: usize, imm: isize },
+    Jal { rd: usize, offset: isize },
+    Jalr { rd: usize, rs1: usize, offset: isize },
+    Branch { cond: BranchCond, rs1: usize, rs2: usize, offset: isize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BranchCond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+fn sext(x: usize, size: usize) -> isize {
+    let shift = core::mem::size_of::<isize>() * 8 - size;
+    ((x << shift) as isize) >> shift
+}
+
+/// Decode a normal (32 bit) instruction that has to be emulated.
+pub fn decode_emulated(insn: u32) -> Option<EmulatedInsn> {
+    let insn = insn as usize;
+    let rd = (insn >> 7) & 0b11111;
+    let rs1 = (insn >> 15) & 0b11111;
+    let rs2 = (insn >> 20) & 0b11111;
+    match insn & 0b1111111 {
+        0b0010111 => Some(EmulatedInsn::Auipc { rd, imm: sext(insn & 0xffff_f000, 32) }),
+        0b1101111 => {
+            let offset = (((insn >> 31) & 0b1) << 20)
+                | (((insn >> 12) & 0b11111111) << 12)
+                | (((insn >> 20) & 0b1) << 11)
+                | (((insn >> 21) & 0b1111111111) << 1);
+            Some(EmulatedInsn::Jal { rd, offset: sext(offset, 21) })
+        }
+        0b1100111 if (insn >> 12) & 0b111 == 0 => {
+            Some(EmulatedInsn::Jalr { rd, rs1, offset: sext(insn >> 20, 12) })
+        }
+        0b1100011 => {
+            let cond = match (insn >> 12) & 0b111 {
+                0b000 => BranchCond::Eq,
+                0b001 => BranchCond::Ne,
+                0b100 => BranchCond::Lt,
+                0b101 => BranchCond::Ge,
+                0b110 => BranchCond::Ltu,
+                0b111 => BranchCond::Geu,
+                _ => return None,
+            };
+            let offset = (((insn >> 31) & 0b1) << 12)
+                | (((insn >> 7) & 0b1) << 11)
+                | (((insn >> 25) & 0b111111) << 5)
+                | (((insn >> 8) & 0b1111) << 1);
+            Some(EmulatedInsn::Branch { cond, rs1, rs2, offset: sext(offset, 13) })
+        }
+        _ => None,
+    }
+}
+
+/// Decode a compressed instruction that has to be emulated.
+pub fn c_decode_emulated(insn: u16) -> Option<EmulatedInsn> {
+    let insn = insn as usize;
+    match c_decode(insn as u16) {
+        Opcode::CJ => {
+            let offset = (((insn >> 12) & 0b1) << 11)
+                | (((insn >> 11) & 0b1) << 4)
+                | (((insn >> 9) & 0b11) << 8)
+                | (((insn >> 8) & 0b1) << 10)
+                | (((insn >> 7) & 0b1) << 6)
+                | (((insn >> 6) & 0b1) << 7)
+                | (((insn >> 3) & 0b111) << 1)
+                | (((insn >> 2) & 0b1) << 5);
+            Some(EmulatedInsn::Jal { rd: 0, offset: sext(offset, 12) })
+        }
+        Opcode::CJR | Opcode::CJALR if (insn >> 7) & 0b11111 == 0 => None,
+        Opcode::CJR => Some(EmulatedInsn::Jalr { rd: 0, rs1: (insn >> 7) & 0b11111, offset: 0 }),
+        Opcode::CJALR => Some(EmulatedInsn::Jalr { rd: 1, rs1: (insn >> 7) & 0b11111, offset: 0 }),
+        Opcode::CBEQZ => Some(c_branch(insn, BranchCond::Eq)),
+        Opcode::CBNEZ => Some(c_branch(insn, BranchCond::Ne)),
+        _ => None,
+    }
+}
+
+fn c_branch(insn: usize, cond: BranchCond) -> EmulatedInsn {
+    let offset = (((insn >> 12) & 0b1) << 8)
+        | (((insn >> 10) & 0b11) << 3)
+        | (((insn >> 5) & 0b11) << 6)
+        | (((insn >> 3) & 0b11) << 1)
+        | (((insn >> 2) & 0b1) << 5);
+    EmulatedInsn::Branch { cond, rs1: ((insn >> 7) & 0b111) + 8, rs2: 0, offset: sext(offset, 9) }
+}
+
+impl EmulatedInsn {
+    /// Execute the instruction found at `pc` with `length` bytes on `cx`, leaving `sepc`
+    /// at the next instruction to run.
+    pub fn emulate(&self, cx: &mut TrapContext, pc: usize, length: usize) {
+        let next = pc.wrapping_add(length);
+        cx.sepc = match *self {
+            EmulatedInsn::Auipc { rd, imm } => {
+                set_reg(cx, rd, pc.wrapping_add(imm as usize));
+                next
+            }
+            EmulatedInsn::Jal { rd, offset } => {
+                set_reg(cx, rd, next);
+                pc.wrapping_add(offset as usize)
+            }
+            EmulatedInsn::Jalr { rd, rs1, offset } => {
+                // read rs1 first, it may be the same register as rd
+                let target = cx.x[rs1].wrapping_add(offset as usize) & !1;
+                set_reg(cx, rd, next);
+                target
+            }
+            EmulatedInsn::Branch { cond, rs1, rs2, offset } => {
+                let (a, b) = (cx.x[rs1], cx.x[rs2]);
+                let taken = match cond {
+                    BranchCond::Eq => a == b,
+                    BranchCond::Ne => a != b,
+                    BranchCond::Lt => (a as isize) < (b as isize),
+                    BranchCond::Ge => (a as isize) >= (b as isize),
+                    BranchCond::Ltu => a < b,
+                    BranchCond::Geu => a >= b,
+                };
+                if taken { pc.wrapping_add(offset as usize) } else { next }
+            }
+        };
+    }
+}
+
+fn set_reg(cx: &mut TrapContext, rd: usize, value: usize) {
+    if rd != 0 {
+        cx.x[rd] = value;
+    }
+}
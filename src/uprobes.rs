@@ -1,425 +1,1466 @@
 use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 //use core::borrow::BorrowMut;
-use core::cell::RefCell;
 //use core::convert::TryInto;
 use core::ops::FnMut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 //use core::pin::Pin;
-use core::slice::{from_raw_parts, from_raw_parts_mut};
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 use lazy_static::*;
-use core::arch::asm;
-use crate::{get_new_page, os_copy_from_user, os_copy_to_user};
-use crate::set_writeable;
-use crate::get_exec_path;
+use crate::os::{os, FileId};
 extern crate trap_context_riscv;
 use trap_context_riscv::TrapContext;
-// extern "C" {
-//     fn get_new_page(addr: usize, len: usize) -> usize;
-//     fn set_writeable(addr: usize);
-//     fn get_exec_path() -> String;
-// }
-
-#[cfg(feature = "rCore-Plus")]
-use {
-    rcore_memory::memory_set::MemoryAttr,
-    rcore_memory::memory_set::handler::{Delay, ByFrame},
-    rcore_memory::paging::PageTable,
-    crate::memory::{AccessType, handle_page_fault_ext, GlobalFrameAlloc},
-    crate::process::current_thread,
-};
 
 
 use crate::riscv_insn_decode::{insn_decode, InsnStatus, get_insn_length};
-use super::probes::{get_sp, ProbeType};
+use crate::riscv_insn_emulate::EmulatedInsn;
+use super::probes::{ForkPolicy, ProbeType};
+use crate::error::UprobeError;
+use crate::latency::LatencyHistogram;
+use crate::xol::XolArea;
 
-use trapframe::{UserContext};
+/// Called when a probe is hit, with the probed address. Captured state lives as long as the probe.
+pub type UprobeHandler = Arc<Mutex<dyn FnMut(&mut TrapContext, usize) + Send>>;
+
+/// Called after the probed instruction ran (`Insn`), when the probed function returns (`SyncFunc`)
+/// or when the polled future is ready (`AsyncFunc`).
+pub type UprobePostHandler = Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>;
+
+/// Called at the entry of a function probed with [`uretprobe_register`], after the `Uprobe` handlers.
+/// May fill the instance data; returning `false` skips the return handler for this call.
+pub type UretprobeEntryHandler = Arc<Mutex<dyn FnMut(&mut TrapContext, &mut UretprobeInstance) -> bool + Send>>;
+
+/// Called when a function probed with [`uretprobe_register`] returns, with the instance of the call.
+pub type UretprobeHandler = Arc<Mutex<dyn FnMut(&mut TrapContext, &UretprobeInstance) + Send>>;
+
+/// Called on the events of the futures polled by a function probed with [`uprobe_register_async`].
+pub type AsyncHandler = Arc<Mutex<dyn FnMut(&mut TrapContext, AsyncEvent, &AsyncFuture) + Send>>;
+
+/// The most entry data a return probe can keep per call.
+pub const UPROBE_MAX_DATA_SIZE: usize = 64;
+
+#[derive(Clone)]
 pub struct Uprobes {
-    pub inner: RefCell<BTreeMap<usize, UprobesInner>>,
+    pub inner: BTreeMap<usize, UprobesInner>,
 }
 
+/// What the probes have in one process: its XOL area and what its threads are in the middle of.
 struct CurrentUprobes{
-    inner: RefCell<BTreeMap<usize, UprobesInner>> ,
+    /// By thread id. Calls return in LIFO order within a thread, but not across threads.
+    threads: BTreeMap<usize, ThreadUprobes>,
+    /// Futures polled but not ready yet, by consumer id and `self` pointer.
+    futures: BTreeMap<(usize, usize), AsyncFuture>,
+    /// Mapped by the process itself, at its first probe hit unless it is the one registering.
+    xol: XolArea,
+    /// Where every probed function of the process returns to, 0 until the area is mapped.
+    trampoline: usize,
+    /// Probes taken out of the process when it was forked, which are never armed in it again.
+    stripped: BTreeSet<usize>,
+    /// Where our breakpoints are in the text of the process. Only these traps are probe hits,
+    /// a breakpoint anywhere else is not ours even at the address of a probe.
+    armed: BTreeSet<usize>,
+    /// Our breakpoints that could not be removed from another process, with the instruction
+    /// they replaced and its length. The process puts it back itself at its next trap there.
+    leftover: BTreeMap<usize, ([u8; 4], usize)>,
 }
 
-struct CurrentProcessUprobesInner{
-    uprobes: Uprobes,
-    current_uprobes: CurrentUprobes,
+/// What one thread is in the middle of. Kept from its first probe hit until the thread or the
+/// process exits, so that later hits reuse its slot and what its stacks have grown to instead of allocating.
+#[derive(Default)]
+struct ThreadUprobes {
+    /// 0 until the first single step. A thread single steps one instruction at a time,
+    /// so it reuses its slot for every probe and never shares it with another thread.
+    slot: usize,
+    step: Option<InsnStep>,
+    /// Calls into probed functions that have not returned yet, the innermost last.
+    returns: Vec<PendingReturn>,
+    /// The handlers of `returns` with the id of their consumer, those of the innermost call last.
+    /// Within a call they are in reverse registration order, so that they run in order as they are popped.
+    handlers: Vec<(usize, ReturnHandler)>,
+}
+
+/// A thread executing a probed instruction in its slot.
+struct InsnStep {
+    addr: usize,
+    /// Where the `c.ebreak` after the instruction in the thread slot is.
+    ebreak_addr: usize,
+    /// Where to continue once the instruction ran.
+    resume_addr: usize,
+}
+
+/// The probes of one executable file.
+struct FileUprobes{
+    /// Never changed in place: writers publish a modified copy, so the trap path can keep
+    /// using the snapshot it took without holding a lock while handlers run.
+    uprobes: RwLock<Arc<Uprobes>>,
+}
+
+/// A live process with the probes of its executable armed.
+struct ProcessUprobes{
+    /// The executable the process runs.
+    file: FileId,
+    /// The probes of `file`, so that a probe hit only looks up the process.
+    probes: Arc<FileUprobes>,
+    current_uprobes: Mutex<CurrentUprobes>,
 }
 
 struct CurrentProcessUprobes{
-    inner: RefCell<BTreeMap<String, CurrentProcessUprobesInner>>,
+    /// Probe definitions, by the file they are in, whatever path it was reached by.
+    files: RwLock<BTreeMap<FileId, Arc<FileUprobes>>>,
+    /// Runtime state, by address space. Every process here runs a file of `files`.
+    processes: RwLock<BTreeMap<usize, Arc<ProcessUprobes>>>,
+    /// Serializes writers, which may take a while to patch user memory.
+    /// Probe hits only take `processes` and `uprobes` for long enough to clone an `Arc`.
+    update: Mutex<()>,
 }
 
 #[derive(Clone)]
 pub struct UprobesInner {
     pub addr: usize,
+    /// 0 until the probed instruction was read, from the first process the probe is armed in.
     pub length: usize,
-    pub slot_addr: usize,
-    pub addisp: usize,
-    pub func_ra: Vec<usize>,
-    pub func_ebreak_addr: usize,
-    pub insn_ebreak_addr: usize,
-    pub handler: Arc<Mutex<for<'r> fn(&'r mut TrapContext,usize) >>, //tag: uprobe_handler
-    pub post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>>,
+    /// The probed instruction, `length` bytes of it are valid. Every process running the
+    /// executable has the same one, so it is written back wherever the probe is disarmed.
+    pub insn: [u8; 4],
+    /// Set when the probed instruction depends on the pc and is emulated instead of single stepped.
+    pub emulate: Option<EmulatedInsn>,
     pub probe_type: ProbeType,
+    pub fork_policy: ForkPolicy,
+    /// Everyone who registered at this address, in registration order.
+    pub consumers: Vec<UprobeConsumer>,
 }
 
+/// One registration on a probed address.
+#[derive(Clone)]
+pub struct UprobeConsumer {
+    /// Tells this registration apart from the others at the same address.
+    pub id: usize,
+    pub handler: Option<UprobeHandler>, //tag: uprobe_handler
+    pub post_handler: Option<UprobePostHandler>,
+    /// Set for consumers from [`uretprobe_register`].
+    pub uretprobe: Option<UretprobeConsumer>,
+    /// Set for consumers from [`uprobe_register_latency`].
+    pub latency: Option<Arc<Mutex<LatencyHistogram>>>,
+    /// Set for consumers from [`uprobe_register_async`].
+    pub asynchronous: Option<(AsyncHandler, PollAbi)>,
+    /// A disabled consumer keeps its handlers but is not called.
+    /// The breakpoint is armed as long as one consumer is enabled.
+    pub enabled: bool,
+}
 
-unsafe impl Sync for Uprobes {}
-unsafe impl Sync for UprobesInner {}
-unsafe impl Sync for CurrentUprobes {}
-unsafe impl Sync for CurrentProcessUprobes {}
-unsafe impl Sync for CurrentProcessUprobesInner {}
+/// The return side of a consumer from [`uretprobe_register`].
+#[derive(Clone)]
+pub struct UretprobeConsumer {
+    pub entry_handler: Option<UretprobeEntryHandler>,
+    pub handler: UretprobeHandler,
+    pub data_size: usize,
+}
 
-lazy_static! {
-    pub static ref UPROBES: Uprobes = Uprobes::new();
+/// One call of a function probed with [`uretprobe_register`], from its entry to its return.
+#[derive(Clone)]
+pub struct UretprobeInstance {
+    func: usize,
+    ret_addr: usize,
+    args: [usize; 8],
+    data_size: usize,
+    data: [u8; UPROBE_MAX_DATA_SIZE],
 }
 
+/// What happened to a future in its `poll` function, or in its drop glue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsyncEvent {
+    /// `poll` was entered for a future not seen before.
+    FirstPoll,
+    /// `poll` was entered again, after the future returned `Pending`.
+    Resume,
+    /// `poll` returned `Ready`, the future is forgotten.
+    Ready,
+    /// The future was dropped before it was ready, as seen by [`uprobe_register_async_drop`].
+    /// It is forgotten, so a future later polled at the same address is a new one.
+    Dropped,
+}
+
+/// A future seen by an async probe, from its first poll until it is ready.
+#[derive(Clone, Debug)]
+pub struct AsyncFuture {
+    self_ptr: usize,
+    polls: usize,
+    first_poll_time: Option<u64>,
+}
+
+/// How a probed `poll` function passes `self` and returns `Poll<T>`.
+#[derive(Clone, Copy)]
+pub struct PollAbi {
+    /// The argument register holding `self`: 0 for `a0`, or 1 when `Poll<T>` is too big for
+    /// registers and returned through memory pointed to by `a0`.
+    pub self_arg: usize,
+    /// Whether `poll` returned `Ready`, given the trap context at the return and `a0` to `a7` at the entry.
+    pub is_ready: fn(&TrapContext, &[usize; 8]) -> bool,
+}
+
+/// A call into a function with return handlers that has not returned yet.
+#[derive(Clone)]
+pub struct PendingReturn {
+    pub ra: usize,
+    /// The probed function.
+    pub func: usize,
+    /// How many of the handlers of the thread are this call's. Taken from the consumers
+    /// enabled at the entry, so that each call gets the handlers it started with.
+    handlers: usize,
+}
+
+#[derive(Clone)]
+enum ReturnHandler {
+    Post(UprobePostHandler),
+    Uretprobe(UretprobeHandler, UretprobeInstance),
+    /// Waiting for the future at `self_ptr` to return from `poll`.
+    Async { handler: AsyncHandler, abi: PollAbi, args: [usize; 8], self_ptr: usize },
+    /// A plain post handler on an `AsyncFunc` probe, called when the future is ready.
+    PostIfReady(UprobePostHandler, [usize; 8]),
+}
+
+/// What [`uprobes_trap_handler`] made of a breakpoint trap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UprobeTrapResult {
+    /// The breakpoint was ours: return to user mode with the trap context as it was left.
+    Handled,
+    /// Not a breakpoint of ruprobes, e.g. an `ebreak` of the program itself. The trap context
+    /// is untouched, the trap is the OS's to handle as it would without ruprobes.
+    NotOurs,
+    /// The breakpoint was ours but could not be handled, so the thread can not go on as if
+    /// nothing happened. Handlers may have run and the trap context may be changed.
+    Error(UprobeError),
+}
+
+/// Handle to a registered probe, returned by [`uprobe_register`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProbeId {
+    path: String,
+    file: FileId,
+    addr: usize,
+    id: usize,
+}
+
+static NEXT_PROBE_ID: AtomicUsize = AtomicUsize::new(1);
+
 lazy_static! {
     static ref CURRENT_PROCESS_UPROBES: CurrentProcessUprobes = CurrentProcessUprobes::new();
 }
 
-// error[E0787]: asm in naked functions must use `noreturn` option
-//   --> /home/oslab/ruprobes/src/uprobes.rs:86:9
-//    |
-// 86 |         asm!("c.ebreak", "c.ebreak");
-//    |         ^^^^^^^^^^^^^^^^^^^^^^^^^^^^
-//    |
-// help: consider specifying that the asm block is responsible for returning from the function
-//    |
-// 86 |         asm!("c.ebreak", "c.ebreak", options(noreturn));
-//    |                                    +++++++++++++++++++
+/// `c.ebreak` twice, enough to cover both compressed and normal instructions
+const EBREAK: [u8; 4] = [0x02, 0x90, 0x02, 0x90];
 
-#[naked]
-extern "C" fn __ebreak() {
-    unsafe {
-        asm!("c.ebreak", "c.ebreak", options(noreturn));
-    }
-}
+/// The uncompressed `ebreak`, which user programs may contain.
+const EBREAK_32: u32 = 0x0010_0073;
 
 impl CurrentProcessUprobes{
     fn new() -> Self{
         Self{
-            inner: RefCell::new(BTreeMap::new()),
+            files: RwLock::new(BTreeMap::new()),
+            processes: RwLock::new(BTreeMap::new()),
+            update: Mutex::new(()),
         }
     }
 
+    fn file(&self, file: FileId) -> Option<Arc<FileUprobes>> {
+        self.files.read().get(&file).cloned()
+    }
+
+    fn process(&self, pid: usize) -> Option<Arc<ProcessUprobes>> {
+        self.processes.read().get(&pid).cloned()
+    }
+
+    /// The processes running `file` with its probes armed.
+    fn processes_of(&self, file: FileId) -> Vec<(usize, Arc<ProcessUprobes>)> {
+        self.processes.read().iter()
+            .filter(|(_, process)| process.file == file)
+            .map(|(&pid, process)| (pid, process.clone()))
+            .collect()
+    }
+
+    /// Read-copy-update the probes of `file`. `f` works on a copy which is only
+    /// published when it succeeds; a file left without probes is dropped with the last
+    /// process it has.
+    ///
+    /// Breakpoints may be armed before the copy is published, and disarmed before a probe goes
+    /// away with it. A hart finding no probe for its breakpoint waits for the writer and looks again.
+    fn update<R>(
+        &self,
+        file: FileId,
+        f: impl FnOnce(&mut Uprobes) -> Result<R, UprobeError>,
+    ) -> Result<R, UprobeError> {
+        let _update = self.update.lock();
+        let entry = self.files.write()
+            .entry(file)
+            .or_insert_with(|| Arc::new(FileUprobes::new()))
+            .clone();
+        let mut uprobes = Uprobes::clone(&entry.uprobes.read());
+        let result = f(&mut uprobes);
+        if result.is_ok() {
+            *entry.uprobes.write() = Arc::new(uprobes);
+        }
+        if entry.uprobes.read().inner.is_empty() {
+            let pid = os().current_pid();
+            let mut processes = self.processes.write();
+            processes.retain(|&owner, process| {
+                // the areas of other processes can not be unmapped from here, they keep them
+                // for the next probe of the file until they exit
+                if process.file != file || owner != pid {
+                    return true;
+                }
+                let mut current_uprobes = process.current_uprobes.lock();
+                // a thread still stepping or returning needs the area and finds its way back through it
+                if current_uprobes.threads.values().any(ThreadUprobes::is_busy) {
+                    return true;
+                }
+                // a thread still running handlers holds on to the process, and finds no area
+                current_uprobes.unmap_xol();
+                false
+            });
+            if processes.values().all(|process| process.file != file) {
+                self.files.write().remove(&file);
+            }
+        }
+        result
+    }
+
+    /// Arm every probe of `file` in process `pid`, which runs it and has none of them yet.
+    /// Probes that can not be armed are left out. The current process maps its XOL area right
+    /// away rather than at its first probe hit.
+    fn attach(&self, pid: usize, file: FileId, uprobes: &mut Uprobes) {
+        // `update` keeps the entry of the file while it runs
+        let probes = match self.file(file) {
+            Some(probes) => probes,
+            None => return,
+        };
+        let process = Arc::new(ProcessUprobes::new(file, probes));
+        for probe in uprobes.inner.values_mut() {
+            match probe.add_uprobepoint(pid) {
+                Ok(()) if probe.has_enabled_consumers() => {
+                    process.current_uprobes.lock().armed.insert(probe.addr);
+                }
+                Ok(()) => {}
+                Err(err) => error!("uprobes: failed to arm probe at {:#x} in process {}: {}", probe.addr, pid, err),
+            }
+        }
+        if pid == os().current_pid() {
+            if let Some(&addr) = uprobes.inner.keys().next() {
+                if let Err(err) = process.current_uprobes.lock().current_space(addr) {
+                    error!("uprobes: failed to map the XOL area of process {}: {}", pid, err);
+                }
+            }
+        }
+        self.processes.write().insert(pid, process);
+    }
+
+    /// Arm `probe` in every process running `file` that does not have it yet, except those it
+    /// was stripped from, or disarm it wherever it is armed. Other processes are left out of
+    /// arming when the kernel can not write to them, and remove the breakpoint themselves.
+    /// On failure the processes done so far are put back as they were.
+    fn patch(&self, file: FileId, probe: &UprobesInner, arm: bool) -> Result<(), UprobeError> {
+        let processes: Vec<(usize, Arc<ProcessUprobes>)> = self.processes_of(file).into_iter()
+            .filter(|(_, process)| {
+                let current_uprobes = process.current_uprobes.lock();
+                if arm {
+                    !current_uprobes.armed.contains(&probe.addr) && !current_uprobes.stripped.contains(&probe.addr)
+                } else {
+                    current_uprobes.armed.contains(&probe.addr)
+                }
+            })
+            .collect();
+        let flip = |pid: usize, process: &ProcessUprobes, arm: bool| -> Result<(), UprobeError> {
+            let mut current_uprobes = process.current_uprobes.lock();
+            if arm {
+                // the breakpoint is still there when the process did not trap on it since
+                if !current_uprobes.armed.contains(&probe.addr) && current_uprobes.leftover.remove(&probe.addr).is_none() {
+                    match probe.arm(pid) {
+                        // it gets the probe when it execs the file again
+                        Err(UprobeError::MissingOsHook) => return Ok(()),
+                        // somebody else's, which the process keeps
+                        Err(UprobeError::ExistingBreakpoint) => {
+                            warn!("uprobes: probe at {:#x} left out of process {}, which has a breakpoint there", probe.addr, pid);
+                            return Ok(());
+                        }
+                        result => result?,
+                    }
+                }
+                current_uprobes.armed.insert(probe.addr);
+            } else if current_uprobes.armed.remove(&probe.addr) {
+                match probe.disarm(pid) {
+                    Err(UprobeError::MissingOsHook) => {
+                        current_uprobes.leftover.insert(probe.addr, (probe.insn, probe.length));
+                    }
+                    Err(err) => {
+                        current_uprobes.armed.insert(probe.addr);
+                        return Err(err);
+                    }
+                    Ok(()) => {}
+                }
+            }
+            Ok(())
+        };
+        for (i, (pid, process)) in processes.iter().enumerate() {
+            if let Err(err) = flip(*pid, process, arm) {
+                for (pid, process) in &processes[..i] {
+                    let _ = flip(*pid, process, !arm);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
     fn uprobes_init(&self){
         info!("uprobes_init");
-        unsafe{
-            let my_path = get_exec_path();
-            if let Some(inner) = self.inner.borrow().get(&my_path){
-                inner.uprobes.add_uprobepoint();
+        let pid = os().current_pid();
+        // whatever the process had before went away with its address space, forgotten under
+        // the update lock so that no writer patches the new image for the old executable
+        match os().exec_file_id() {
+            Ok(file) if self.file(file).is_some() => {
+                let _ = self.update(file, |uprobes| {
+                    self.processes.write().remove(&pid);
+                    self.attach(pid, file, uprobes);
+                    Ok(())
+                });
+            }
+            result => {
+                let _update = self.update.lock();
+                self.processes.write().remove(&pid);
+                if let Err(err) = result {
+                    error!("uprobes: failed to identify the executable of process {}: {}", pid, err);
+                }
             }
         }
     }
 
+    /// Register `consumer` on the file at `path`, whatever path the processes running it were started by.
+    fn register(&self, path: String, addr: usize, consumer: UprobeConsumer, probe_type: ProbeType) -> Result<ProbeId, UprobeError> {
+        let file = os().file_id(&path)?;
+        let id = self.register_uprobes(file, addr, consumer, probe_type)?;
+        Ok(ProbeId { path, file, addr, id })
+    }
+
     pub fn register_uprobes(
         &self,
-        path: String,
+        file: FileId,
         addr: usize,
-        handler: Arc<Mutex<for<'r> fn(&'r mut TrapContext,usize) >>, //tag: uprobe_handler
-        post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>>,
+        consumer: UprobeConsumer,
         probe_type: ProbeType
-    ) -> isize {
-        let mut uprobes_inner: core::cell::RefMut<'_, BTreeMap<String, CurrentProcessUprobesInner>> = self.inner.borrow_mut();
-        if let Some(inner) = uprobes_inner.get_mut(&path.clone()){
-            inner.uprobes.register_uprobe(addr, handler, post_handler, probe_type);
-        }
-        else{
-            let uprobes = Uprobes::new();
-            info!("uprobes: add new path");
-            uprobes.register_uprobe(addr, handler, post_handler, probe_type);
-            let current_uprobes = CurrentUprobes::new();
-            uprobes_inner.insert(path.clone(), CurrentProcessUprobesInner{
-                uprobes,
-                current_uprobes,
-            });
-            info!("uprobes: insert success");
-        }
-        info!("uprobes: path={}", unsafe{get_exec_path()});
-        unsafe{
-            if path == get_exec_path(){
-                info!("uprobes: path=execpath");
-                uprobes_inner.get_mut(&path.clone()).unwrap().uprobes.inner.borrow_mut().get_mut(&addr).unwrap().add_uprobepoint();
-                info!("uprobes: path=execpath, add sucess");
-            }}
-        0
-    }
-
-    unsafe fn uprobes_trap_handler(&self, trap_context: &mut TrapContext){
-        let path = get_exec_path();
-        let mut uprobes_inner = self.inner.borrow_mut();
-        let mut uprobes = uprobes_inner.get(&path.clone()).unwrap().uprobes.inner.borrow_mut();
-        let mut current_uprobes = uprobes_inner.get(&path.clone()).unwrap().current_uprobes.inner.borrow_mut();
-        match uprobes.get_mut(&trap_context.sepc) {
+    ) -> Result<usize, UprobeError> {
+        self.update(file, |uprobes| {
+            let id = uprobes.register_uprobe(addr, consumer, probe_type)?;
+            // processes running the file without any probe so far get all of them
+            for pid in live_processes(file) {
+                // a process known to run another file exec'ed this one since
+                if self.process(pid).is_none_or(|process| process.file != file) {
+                    self.attach(pid, file, uprobes);
+                }
+            }
+            let probe = uprobes.inner.get_mut(&addr).unwrap();
+            // a failing consumer is not kept, as the copy is thrown away
+            if let Some(pid) = self.any_process(file) {
+                info!("uprobes: arming probe at {:#x}", addr);
+                // the next process running the file reads the instruction and gets the probe
+                match probe.prepare(pid) {
+                    Err(UprobeError::MissingOsHook) => {
+                        warn!("uprobes: can not read probe at {:#x} from process {}, deferring it", addr, pid);
+                        return Ok(id);
+                    }
+                    result => result?,
+                }
+                // every earlier consumer is disabled or there is none, so the breakpoint is not in place
+                if probe.consumers.iter().filter(|consumer| consumer.enabled).count() == 1 {
+                    if let Err(err) = self.patch(file, probe, true) {
+                        // processes attached above got it already
+                        let _ = self.patch(file, probe, false);
+                        return Err(err);
+                    }
+                }
+                info!("uprobes: arming probe at {:#x}, add sucess", addr);
+            }
+            Ok(id)
+        }).inspect_err(|err| {
+            error!("uprobes: failed to register probe at {:#x}: {}", addr, err);
+        })
+    }
+
+    /// A process running `file` to read the probed instructions from, preferably the current one.
+    fn any_process(&self, file: FileId) -> Option<usize> {
+        let processes = self.processes_of(file);
+        let pid = os().current_pid();
+        if processes.iter().any(|&(owner, _)| owner == pid) {
+            Some(pid)
+        } else {
+            processes.first().map(|&(owner, _)| owner)
+        }
+    }
+
+    /// With `id` only that consumer is removed, and the breakpoint only goes away with the
+    /// last one. Without it the breakpoint is removed with all of its consumers.
+    pub fn unregister_uprobes(&self, file: FileId, addr: usize, id: Option<usize>) -> Result<(), UprobeError> {
+        if self.file(file).is_none() {
+            return Err(UprobeError::UnknownPath);
+        }
+        self.update(file, |uprobes| {
+            if let Some(id) = id {
+                let probe = uprobes.inner.get_mut(&addr).ok_or(UprobeError::NotRegistered)?;
+                let index = probe.consumers.iter().position(|consumer| consumer.id == id).ok_or(UprobeError::NotRegistered)?;
+                if probe.consumers.len() > 1 {
+                    let consumer = probe.consumers.remove(index);
+                    let mut count = 0;
+                    for (_, process) in self.processes_of(file) {
+                        let mut current_uprobes = process.current_uprobes.lock();
+                        current_uprobes.futures.retain(|&(owner, _), _| owner != id);
+                        for thread in current_uprobes.threads.values_mut() {
+                            count += thread.forget_handlers(|_, owner| owner == id);
+                        }
+                    }
+                    if count != 0 {
+                        warn!("uprobes: dropping the handlers of consumer {} from {} pending return(s) of probe at {:#x}", id, count, addr);
+                    }
+                    if consumer.enabled && !probe.has_enabled_consumers() && probe.length != 0 {
+                        self.patch(file, probe, false)?;
+                    }
+                    info!("uprobes: unregister success");
+                    return Ok(());
+                }
+            }
+            let probe = uprobes.unregister_uprobe(addr).ok_or(UprobeError::NotRegistered)?;
+            // steps in flight do not need the probe to finish
+            let mut count = 0;
+            for (_, process) in self.processes_of(file) {
+                let mut current_uprobes = process.current_uprobes.lock();
+                current_uprobes.futures.retain(|&(owner, _), _| probe.consumers.iter().all(|consumer| consumer.id != owner));
+                for thread in current_uprobes.threads.values_mut() {
+                    // the call still has to get back to its caller through the trampoline
+                    count += thread.forget_handlers(|func, _| func == addr);
+                }
+            }
+            if count != 0 {
+                warn!("uprobes: dropping the handlers of {} pending return(s) of probe at {:#x}", count, addr);
+            }
+            // the breakpoint only exists in the processes the probe was armed in
+            if probe.length != 0 && probe.has_enabled_consumers() {
+                self.patch(file, &probe, false)?;
+            }
+            info!("uprobes: unregister success");
+            Ok(())
+        })
+    }
+
+    fn set_enabled(&self, probe_id: &ProbeId, enabled: bool) -> Result<(), UprobeError> {
+        if self.file(probe_id.file).is_none() {
+            return Err(UprobeError::UnknownPath);
+        }
+        self.update(probe_id.file, |uprobes| {
+            let probe = uprobes.inner.get_mut(&probe_id.addr).ok_or(UprobeError::NotRegistered)?;
+            let was_armed = probe.has_enabled_consumers();
+            let consumer = probe.consumers.iter_mut().find(|consumer| consumer.id == probe_id.id).ok_or(UprobeError::NotRegistered)?;
+            consumer.enabled = enabled;
+            // the probe keeps the original instruction, so arming and disarming only swaps the probed bytes
+            if was_armed != probe.has_enabled_consumers() && probe.length != 0 {
+                self.patch(probe_id.file, probe, enabled)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_fork_policy(&self, probe_id: &ProbeId, fork_policy: ForkPolicy) -> Result<(), UprobeError> {
+        if self.file(probe_id.file).is_none() {
+            return Err(UprobeError::UnknownPath);
+        }
+        self.update(probe_id.file, |uprobes| {
+            let probe = uprobes.inner.get_mut(&probe_id.addr).ok_or(UprobeError::NotRegistered)?;
+            if !probe.consumers.iter().any(|consumer| consumer.id == probe_id.id) {
+                return Err(UprobeError::NotRegistered);
+            }
+            probe.fork_policy = fork_policy;
+            Ok(())
+        })
+    }
+
+    /// The child gets a copy of the address space of `parent`, with the calls in flight of the
+    /// forking thread. Probes that are stripped get their original instruction back in the child.
+    fn uprobes_fork(&self, parent: usize, child: usize, child_tid: usize) -> Result<(), UprobeError> {
+        let _update = self.update.lock();
+        let process = match self.process(parent) {
+            Some(process) => process,
+            None => return Ok(()),
+        };
+        let uprobes = process.probes.uprobes.read().clone();
+        let current_uprobes = process.current_uprobes.lock();
+        let mut copy = CurrentUprobes {
+            xol: current_uprobes.xol.clone(),
+            trampoline: current_uprobes.trampoline,
+            stripped: current_uprobes.stripped.clone(),
+            // the child got the breakpoints of the parent with its address space
+            armed: current_uprobes.armed.clone(),
+            leftover: current_uprobes.leftover.clone(),
+            ..CurrentUprobes::new()
+        };
+        for probe in uprobes.inner.values().filter(|probe| probe.fork_policy == ForkPolicy::Strip) {
+            if copy.armed.remove(&probe.addr) {
+                // the child takes the breakpoint out itself at its next trap there
+                if let Err(err) = os().copy_to_process(child, probe.addr, &probe.insn[..probe.length]) {
+                    if err != UprobeError::MissingOsHook {
+                        warn!("uprobes: failed to strip probe at {:#x} from process {}: {}", probe.addr, child, err);
+                    }
+                    copy.leftover.insert(probe.addr, (probe.insn, probe.length));
+                }
+            }
+            copy.stripped.insert(probe.addr);
+        }
+        let inherited = |addr: usize| uprobes.inner.get(&addr).is_some_and(|probe| probe.fork_policy == ForkPolicy::Inherit);
+        let forking = os().current_thread_id();
+        if let Some(thread) = current_uprobes.threads.get(&forking) {
+            let mut thread = ThreadUprobes {
+                // the slot of the forking thread is taken in the copy of the area as well
+                slot: thread.slot,
+                step: None,
+                returns: thread.returns.clone(),
+                handlers: thread.handlers.clone(),
+            };
+            // the child still returns through the trampoline, only without calling anybody
+            thread.forget_handlers(|func, _| !inherited(func));
+            copy.threads.insert(child_tid, thread);
+        }
+        copy.futures = current_uprobes.futures.iter()
+            .filter(|((id, _), _)| {
+                uprobes.inner.values().any(|probe| {
+                    probe.fork_policy == ForkPolicy::Inherit && probe.consumers.iter().any(|consumer| consumer.id == *id)
+                })
+            })
+            .map(|(&key, future)| (key, future.clone()))
+            .collect();
+        let child_process = ProcessUprobes { file: process.file, probes: process.probes.clone(), current_uprobes: Mutex::new(copy) };
+        self.processes.write().insert(child, Arc::new(child_process));
+        Ok(())
+    }
+
+    /// Forget the calls, steps, futures and XOL area of the exiting process, so that nothing
+    /// of it is found by a later process with the same ids.
+    fn uprobes_exit(&self) {
+        let _update = self.update.lock();
+        self.processes.write().remove(&os().current_pid());
+    }
+
+    /// Forget the calls and step in flight of thread `tid` of the current process, so that a
+    /// later thread with the same id does not return through them, and give back its slot.
+    fn uprobes_thread_exit(&self, tid: usize) {
+        if let Some(process) = self.process(os().current_pid()) {
+            let mut current_uprobes = process.current_uprobes.lock();
+            if let Some(thread) = current_uprobes.threads.remove(&tid) {
+                if thread.slot != 0 {
+                    current_uprobes.xol.free_slot(thread.slot);
+                }
+            }
+        }
+    }
+
+    fn latency(&self, probe_id: &ProbeId) -> Option<LatencyHistogram> {
+        let entry = self.file(probe_id.file)?;
+        let uprobes = entry.uprobes.read().clone();
+        let consumer = uprobes.inner.get(&probe_id.addr)?.consumers.iter().find(|consumer| consumer.id == probe_id.id)?;
+        let histogram = consumer.latency.as_ref()?.lock().clone();
+        Some(histogram)
+    }
+
+    /// The handler of the consumer from [`uprobe_register_async`] that `probe_id` is.
+    fn async_handler(&self, probe_id: &ProbeId) -> Option<AsyncHandler> {
+        let entry = self.file(probe_id.file)?;
+        let uprobes = entry.uprobes.read().clone();
+        let consumer = uprobes.inner.get(&probe_id.addr)?.consumers.iter().find(|consumer| consumer.id == probe_id.id)?;
+        consumer.asynchronous.as_ref().map(|(handler, _)| handler.clone())
+    }
+
+    /// Forget the future at `self_ptr` that async consumer `id` follows in the current process.
+    fn forget_future(&self, id: usize, self_ptr: usize) -> Option<AsyncFuture> {
+        let process = self.process(os().current_pid())?;
+        let future = process.current_uprobes.lock().futures.remove(&(id, self_ptr));
+        future
+    }
+
+    fn is_enabled(&self, probe_id: &ProbeId) -> bool {
+        self.file(probe_id.file).is_some_and(|entry| {
+            entry.uprobes.read().inner.get(&probe_id.addr).is_some_and(|probe| {
+                probe.consumers.iter().any(|consumer| consumer.id == probe_id.id && consumer.enabled)
+            })
+        })
+    }
+
+    fn uprobes_trap_handler(&self, trap_context: &mut TrapContext) -> UprobeTrapResult {
+        match self.handle_trap(trap_context) {
+            Ok(true) => return UprobeTrapResult::Handled,
+            Ok(false) => {}
+            Err(err) => return UprobeTrapResult::Error(err),
+        }
+        // a writer may have armed the breakpoint without publishing its probe yet, or removed
+        // the probe after the hart trapped: look again once it is done
+        drop(self.update.lock());
+        match self.handle_trap(trap_context) {
+            Ok(true) => UprobeTrapResult::Handled,
+            Ok(false) if is_breakpoint(trap_context.sepc) => UprobeTrapResult::NotOurs,
+            // the breakpoint that trapped was taken out, run what is there now
+            Ok(false) => UprobeTrapResult::Handled,
+            Err(err) => UprobeTrapResult::Error(err),
+        }
+    }
+
+    /// Handle a breakpoint trap of the current process, `Ok(false)` when nothing of ours is at
+    /// `sepc` and the trap context is left untouched.
+    fn handle_trap(&self, trap_context: &mut TrapContext) -> Result<bool, UprobeError> {
+        let process = match self.process(os().current_pid()) {
+            Some(process) => process,
+            None => return Ok(false),
+        };
+        let uprobes = process.probes.uprobes.read().clone();
+        let thread = os().current_thread_id();
+        // a probe that is disabled, stripped from the process or could not be armed in it has no
+        // breakpoint here, so one at its address is somebody else's, or ours removed while the thread was trapping
+        let armed = |probe: &&UprobesInner| process.current_uprobes.lock().armed.contains(&probe.addr);
+        match uprobes.inner.get(&trap_context.sepc).filter(armed) {
             Some(probe) => {
-                // run user defined handler
-                (probe.handler.lock())(trap_context,trap_context.sepc); //tag: uprobe_handler
-                // single step the probed instruction
-                match probe.probe_type{
-                    ProbeType::SyncFunc =>{
-                        trap_context.x[2] = trap_context.x[2].wrapping_add(probe.addisp);
-                        //cx.general.sp = cx.general.sp.wrapping_add(probe.addisp);
-                        trap_context.sepc = trap_context.sepc.wrapping_add(probe.length);
-                        //cx.sepc = cx.sepc.wrapping_add(probe.length);
-                        if let Some(_) = probe.post_handler{
-                            if !current_uprobes.contains_key(&probe.func_ebreak_addr){
-                                current_uprobes.insert(probe.func_ebreak_addr, probe.clone());
+                // run user defined handlers
+                let addr = trap_context.sepc;
+                for consumer in probe.consumers.iter().filter(|consumer| consumer.enabled) {
+                    if let Some(handler) = &consumer.handler {
+                        (handler.lock())(trap_context, addr); //tag: uprobe_handler
+                    }
+                }
+                // a return probe only takes over ra, the function itself runs unchanged
+                if probe.probe_type != ProbeType::Insn {
+                    let count = probe.enter_function(trap_context, &process.current_uprobes, thread);
+                    if count != 0 {
+                        let mut current_uprobes = process.current_uprobes.lock();
+                        let trampoline = if current_uprobes.armed.contains(&probe.addr) {
+                            current_uprobes.current_space(probe.addr).map(|space| Some(space.trampoline))
+                        } else {
+                            Ok(None)
+                        };
+                        let state = current_uprobes.thread(thread);
+                        let start = state.handlers.len() - count;
+                        let trampoline = match trampoline {
+                            Ok(Some(trampoline)) => trampoline,
+                            // the probe went away while the handlers ran, maybe with the area:
+                            // run the instruction put back in its place
+                            Ok(None) => {
+                                state.handlers.truncate(start);
+                                return Ok(true);
                             }
-                            let current_uprobe: &mut UprobesInner = current_uprobes.get_mut(&probe.func_ebreak_addr).unwrap();
-                            current_uprobe.func_ra.push(trap_context.x[1]);
-                            //current_uprobe.func_ra.push(cx.general.ra);
-                            trap_context.x[1] = probe.func_ebreak_addr as usize;
-                            //cx.general.ra = probe.func_ebreak_addr as usize;
-                        }
-                    },
-                    ProbeType::Insn =>{
-                        trap_context.sepc = probe.slot_addr as usize;
-                        //cx.sepc = probe.slot_addr as usize;
-                        probe.insn_ebreak_addr = trap_context.sepc + probe.length;
-                        //probe.insn_ebreak_addr = cx.sepc + probe.length;
-                        if !current_uprobes.contains_key(&probe.insn_ebreak_addr){
-                            current_uprobes.insert(probe.insn_ebreak_addr, probe.clone());
+                            Err(err) => {
+                                state.handlers.truncate(start);
+                                return Err(err);
+                            }
+                        };
+                        state.handlers[start..].reverse();
+                        let ra = trap_context.x[1];
+                        state.returns.push(PendingReturn { ra, func: probe.addr, handlers: count });
+                        trap_context.x[1] = trampoline;
+                    }
+                }
+                // then run the probed instruction, which already sees the hijacked ra
+                match probe.emulate{
+                    Some(insn) =>{
+                        // nothing to single step, the post handler sees the result right away
+                        insn.emulate(trap_context, probe.addr, probe.length);
+                        if probe.probe_type == ProbeType::Insn {
+                            probe.run_post_handlers(trap_context);
                         }
                     }
-                    ProbeType::AsyncFunc => {
-                        unimplemented!("probing async function is not implemented yet")
+                    None =>{
+                        // the step keeps the area mapped once the lock is dropped
+                        let mut current_uprobes = process.current_uprobes.lock();
+                        if !current_uprobes.armed.contains(&probe.addr) {
+                            return Ok(true);
+                        }
+                        let slot_addr = current_uprobes.thread_slot(thread, probe.addr)?;
+                        // the instruction followed by c.ebreak, in this thread's slot
+                        let mut code = [0u8; 6];
+                        code[..probe.length].copy_from_slice(&probe.insn[..probe.length]);
+                        code[probe.length..probe.length + 2].copy_from_slice(&EBREAK[..2]);
+                        os().copy_to_xol(slot_addr, &code[..probe.length + 2])?;
+                        current_uprobes.thread(thread).step = Some(InsnStep {
+                            addr: probe.addr,
+                            ebreak_addr: slot_addr + probe.length,
+                            resume_addr: probe.addr + probe.length,
+                        });
+                        trap_context.sepc = slot_addr;
                     }
                 }
             }
             None => {
-                match current_uprobes.get_mut(&trap_context.sepc){
-                    Some(probe) =>{
-                        if probe.insn_ebreak_addr == trap_context.sepc{
-                            if let Some(post_handler) = &probe.post_handler{
-                                (post_handler.lock())(trap_context);
+                let sepc = trap_context.sepc;
+                {
+                    // the lock keeps other threads trapping here waiting until the instruction is back
+                    let mut current_uprobes = process.current_uprobes.lock();
+                    if let Some(&(insn, length)) = current_uprobes.leftover.get(&sepc) {
+                        write_text(os().current_pid(), sepc, &insn[..length])?;
+                        current_uprobes.leftover.remove(&sepc);
+                        return Ok(true);
+                    }
+                }
+                // take what we need and drop the lock before calling back into user code
+                let step = process.current_uprobes.lock().threads.get_mut(&thread)
+                    .and_then(|state| state.step.take_if(|step| step.ebreak_addr == sepc));
+                if let Some(step) = step {
+                    // the probe may have been removed while the thread was stepping,
+                    // function probes call their post handlers on return instead
+                    if let Some(probe) = uprobes.inner.get(&step.addr).filter(|probe| probe.probe_type == ProbeType::Insn) {
+                        probe.run_post_handlers(trap_context);
+                    }
+                    trap_context.sepc = step.resume_addr;
+                    return Ok(true);
+                }
+                let pending = {
+                    let mut current_uprobes = process.current_uprobes.lock();
+                    if current_uprobes.trampoline == 0 || sepc != current_uprobes.trampoline {
+                        return Ok(false);
+                    }
+                    current_uprobes.threads.get_mut(&thread)
+                        .and_then(|state| state.returns.pop())
+                        .ok_or(UprobeError::NoPendingReturn)?
+                };
+                for _ in 0..pending.handlers {
+                    // one at a time, the lock is not held while they run
+                    let (id, handler) = match process.current_uprobes.lock().threads.get_mut(&thread).and_then(|state| state.handlers.pop()) {
+                        Some(handler) => handler,
+                        None => break,
+                    };
+                    match handler {
+                        ReturnHandler::Post(post_handler) => (post_handler.lock())(trap_context),
+                        ReturnHandler::Uretprobe(handler, instance) => (handler.lock())(trap_context, &instance),
+                        ReturnHandler::Async { handler, abi, args, self_ptr } => {
+                            if !(abi.is_ready)(trap_context, &args) {
+                                continue;
+                            }
+                            // not there when the consumer went away while the future was being polled
+                            let future = process.current_uprobes.lock().futures.remove(&(id, self_ptr));
+                            if let Some(future) = future {
+                                (handler.lock())(trap_context, AsyncEvent::Ready, &future);
                             }
-                            let sepc = probe.addr + probe.length;
-                            current_uprobes.remove(&trap_context.sepc);
-                            trap_context.sepc = sepc;
                         }
-                        else{
-                            (probe.post_handler.as_ref().unwrap().lock())(trap_context);
-                            trap_context.sepc = probe.func_ra.pop().unwrap();
-                            if probe.func_ra.len() == 0{
-                                current_uprobes.remove(&trap_context.sepc);
+                        ReturnHandler::PostIfReady(post_handler, args) => {
+                            if (PollAbi::default().is_ready)(trap_context, &args) {
+                                (post_handler.lock())(trap_context);
                             }
                         }
                     }
-                    _ => {}
                 }
+                trap_context.sepc = pending.ra;
             }
         }
+        Ok(true)
     }
 }
 
 impl CurrentUprobes{
     fn new() -> Self{
         Self{
-            inner: RefCell::new(BTreeMap::new()),
+            threads: BTreeMap::new(),
+            futures: BTreeMap::new(),
+            xol: XolArea::new(),
+            trampoline: 0,
+            stripped: BTreeSet::new(),
+            armed: BTreeSet::new(),
+            leftover: BTreeMap::new(),
         }
     }
+
+    /// Map the XOL area of the current process near `addr` with the return trampoline, if that
+    /// was not done yet. Only called in the process itself.
+    fn current_space(&mut self, addr: usize) -> Result<&mut Self, UprobeError> {
+        if self.trampoline == 0 {
+            let trampoline = self.xol.alloc_slot(addr)?;
+            if let Err(err) = os().copy_to_xol(trampoline, &EBREAK[..2]) {
+                self.xol.free_slot(trampoline);
+                return Err(err);
+            }
+            self.trampoline = trampoline;
+        }
+        Ok(self)
+    }
+
+    /// Unmap the XOL area of the current process, with the trampoline and the slots in it.
+    fn unmap_xol(&mut self) {
+        self.xol.unmap();
+        self.trampoline = 0;
+        for thread in self.threads.values_mut() {
+            thread.slot = 0;
+        }
+    }
+
+    fn thread(&mut self, thread: usize) -> &mut ThreadUprobes {
+        self.threads.entry(thread).or_default()
+    }
+
+    /// The slot of `thread` of the current process, taken from its XOL area on its first single step.
+    fn thread_slot(&mut self, thread: usize, addr: usize) -> Result<usize, UprobeError> {
+        let slot_addr = self.thread(thread).slot;
+        if slot_addr != 0 {
+            return Ok(slot_addr);
+        }
+        let slot_addr = self.current_space(addr)?.xol.alloc_slot(addr)?;
+        self.thread(thread).slot = slot_addr;
+        Ok(slot_addr)
+    }
+}
+
+impl ThreadUprobes{
+    /// Whether the thread is stepping or returning through the XOL area.
+    fn is_busy(&self) -> bool {
+        self.step.is_some() || !self.returns.is_empty()
+    }
+
+    /// Drop the handlers `forget` accepts, given the probed function of their call and their
+    /// consumer id. The calls still return, without them. Gives how many calls lost handlers.
+    fn forget_handlers(&mut self, forget: impl Fn(usize, usize) -> bool) -> usize {
+        let mut start = 0;
+        let mut count = 0;
+        for call in &mut self.returns {
+            let end = start + call.handlers;
+            // move the handlers kept to the front of the call's, in the order they were
+            let mut kept = start;
+            for i in start..end {
+                if !forget(call.func, self.handlers[i].0) {
+                    self.handlers.swap(kept, i);
+                    kept += 1;
+                }
+            }
+            if kept != end {
+                self.handlers.drain(kept..end);
+                call.handlers = kept - start;
+                count += 1;
+            }
+            start = kept;
+        }
+        count
+    }
+}
+
+impl FileUprobes{
+    fn new() -> Self{
+        Self{
+            uprobes: RwLock::new(Arc::new(Uprobes::new())),
+        }
+    }
+}
+
+impl ProcessUprobes{
+    fn new(file: FileId, probes: Arc<FileUprobes>) -> Self{
+        Self{
+            file,
+            probes,
+            current_uprobes: Mutex::new(CurrentUprobes::new()),
+        }
+    }
+}
+
+/// The processes running `file` the OS knows of, and the current one if it does.
+fn live_processes(file: FileId) -> Vec<usize> {
+    let mut pids = os().processes_running(file);
+    let pid = os().current_pid();
+    if os().exec_file_id() == Ok(file) && !pids.contains(&pid) {
+        pids.push(pid);
+    }
+    pids
+}
+
+/// Whether the current process has `ebreak` or `c.ebreak` at `addr`, which is assumed when
+/// it can not be read.
+fn is_breakpoint(addr: usize) -> bool {
+    let mut insn = [0u8; 4];
+    if os().copy_from_user(addr, &mut insn[..2]).is_err() {
+        return true;
+    }
+    let length = get_insn_length([insn[0], insn[1]]);
+    if length == 4 && os().copy_from_user(addr + 2, &mut insn[2..]).is_err() {
+        return true;
+    }
+    is_ebreak(insn, length)
+}
+
+/// Whether the first `length` bytes of `insn` are `ebreak` or `c.ebreak`.
+fn is_ebreak(insn: [u8; 4], length: usize) -> bool {
+    match length {
+        2 => insn[..2] == EBREAK[..2],
+        4 => u32::from_le_bytes(insn) == EBREAK_32,
+        _ => false,
+    }
+}
+
+/// Read the text of process `pid`, which may not be the current one.
+fn read_text(pid: usize, addr: usize, buf: &mut [u8]) -> Result<(), UprobeError> {
+    if pid == os().current_pid() {
+        os().copy_from_user(addr, buf)
+    } else {
+        os().copy_from_process(pid, addr, buf)
+    }
+}
+
+/// Patch the text of process `pid`, which may not be the current one.
+fn write_text(pid: usize, addr: usize, bytes: &[u8]) -> Result<(), UprobeError> {
+    if pid == os().current_pid() {
+        os().set_writeable(addr)?;
+        os().copy_to_user(addr, bytes)?;
+        os().flush_icache();
+        Ok(())
+    } else {
+        os().copy_to_process(pid, addr, bytes)
+    }
 }
 
 impl UprobesInner {
-    pub fn new(
-        addr: usize,
-        handler: Arc<Mutex<for<'r> fn(&'r mut TrapContext,usize) >>,//tag: uprobe_handler
-        post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>>,
-        probe_type: ProbeType
-    ) -> Option<Self> {
-        Some(Self {
+    pub fn new(addr: usize, probe_type: ProbeType) -> Self {
+        Self {
             addr,
             length: 0,
-            slot_addr: 0,
-            addisp: 0,
-            func_ra: Vec::new(),
-            func_ebreak_addr: 0,
-            insn_ebreak_addr: 0,
-            handler,
-            post_handler,
+            insn: [0; 4],
+            emulate: None,
             probe_type,
-        })
-    }
-
-    unsafe fn add_uprobepoint(&mut self){//这个函数的注释中所说的“改动”是指将对虚拟内存的直接读写用osutil.rs里的os_copy_from_user和os_copy_to_user替换，不是说将这个模块适配到其他os时就一定要替换。
-        // get free point in user stack
-        let addr = self.addr;
-        unsafe {
-            self.func_ebreak_addr = 
-            get_new_page(addr, 2); //get_new_page是通过页表来查找空闲内存的，返回的是空闲的地址，但是对这个地址没有做读或写操作，故不需要改动
-        }
-        unsafe {
-            self.slot_addr = 
-            get_new_page(addr, 6);//不需要改动。理由同上。
-        }//但是，涉及func_ebreak_addr，slot_addr两个指针的读写的部分要改动.
-        //let mut slot: &mut [u8] = unsafe { from_raw_parts_mut(self.slot_addr as *mut u8, 6)};//涉及slot的显然要改动。
-        unsafe{set_writeable(addr);}//不涉及用户内存空间的内存读写，故无需改动。
-        let mut inst_copy:[u8;2]=[0,0];
-        unsafe {
-            os_copy_from_user(addr, &mut (inst_copy[0]), 2);
-        }
-        // let inst = unsafe { from_raw_parts(addr as *const u8, 2) };//涉及insn的要改动。
-        // read the lowest byte of the probed instruction to determine whether it is compressed
-        let length = get_insn_length(addr);//此处已经修复。
-        self.length = length;//无需改动。
-        // save the probed instruction to a buffer
-        unsafe{
-            os_copy_to_user(self.slot_addr, &inst_copy[0], length);
+            fork_policy: ForkPolicy::default(),
+            consumers: Vec::new(),
         }
-        //slot[..length].copy_from_slice(&inst[..length]);
+    }
 
-        // decode the probed instruction to retrive imm
-        let ebreak = unsafe { from_raw_parts(__ebreak as *const u8, 2) };
+    fn has_enabled_consumers(&self) -> bool {
+        self.consumers.iter().any(|consumer| consumer.enabled)
+    }
 
-        match self.probe_type{
-            ProbeType::Insn =>{
-                match insn_decode(addr){
-                    InsnStatus::Legal =>{
-                        unsafe{
-                            os_copy_to_user(self.slot_addr+length, &ebreak[0], 2);
-                        }
-                        //slot[length..length+2].copy_from_slice(ebreak);
-                        self.insn_ebreak_addr = self.slot_addr + length;
-                    },
-                    _ => {warn!("uprobes: instruction is not legal");},
-                }
+    /// Run the entry handlers of return and async probes and push what has to run when the function
+    /// returns on the handlers of `thread`. Gives how many were pushed.
+    fn enter_function(&self, trap_context: &mut TrapContext, current_uprobes: &Mutex<CurrentUprobes>, thread: usize) -> usize {
+        let mut count = 0;
+        let mut push = |id, handler| {
+            current_uprobes.lock().thread(thread).handlers.push((id, handler));
+            count += 1;
+        };
+        let mut args = [0; 8];
+        args.copy_from_slice(&trap_context.x[10..18]);
+        for consumer in self.consumers.iter().filter(|consumer| consumer.enabled) {
+            if let Some(post_handler) = &consumer.post_handler {
+                push(consumer.id, match self.probe_type {
+                    ProbeType::AsyncFunc => ReturnHandler::PostIfReady(post_handler.clone(), args),
+                    _ => ReturnHandler::Post(post_handler.clone()),
+                });
             }
-            ProbeType::SyncFunc =>{
-                unsafe{
-                    os_copy_to_user(self.func_ebreak_addr, &ebreak[0], 2);
-                }
-                // let mut ebreak_ptr = unsafe { from_raw_parts_mut(self.func_ebreak_addr as *mut u8, 2)};
-                // ebreak_ptr.copy_from_slice(ebreak);
-
-                match get_sp(addr){
-                    Some(sp) => self.addisp = sp,
-                    None => {error!("sp not found!");}
+            if let Some((handler, abi)) = &consumer.asynchronous {
+                let self_ptr = args[abi.self_arg];
+                let (event, future) = {
+                    let mut current_uprobes = current_uprobes.lock();
+                    let mut event = AsyncEvent::Resume;
+                    let future = current_uprobes.futures.entry((consumer.id, self_ptr)).or_insert_with(|| {
+                        event = AsyncEvent::FirstPoll;
+                        AsyncFuture { self_ptr, polls: 0, first_poll_time: os().current_time().ok() }
+                    });
+                    future.polls += 1;
+                    (event, future.clone())
+                };
+                (handler.lock())(trap_context, event, &future);
+                push(consumer.id, ReturnHandler::Async { handler: handler.clone(), abi: *abi, args, self_ptr });
+            }
+            if let Some(uretprobe) = &consumer.uretprobe {
+                let mut instance = UretprobeInstance::new(self.addr, trap_context, uretprobe.data_size);
+                let wanted = match &uretprobe.entry_handler {
+                    Some(entry_handler) => (entry_handler.lock())(trap_context, &mut instance),
+                    None => true,
+                };
+                if wanted {
+                    push(consumer.id, ReturnHandler::Uretprobe(uretprobe.handler.clone(), instance));
                 }
             }
-            ProbeType::AsyncFunc =>{
-                error!("not implemented yet!");
+        }
+        count
+    }
+
+    fn run_post_handlers(&self, trap_context: &mut TrapContext) {
+        for consumer in self.consumers.iter().filter(|consumer| consumer.enabled) {
+            if let Some(post_handler) = &consumer.post_handler {
+                (post_handler.lock())(trap_context);
             }
         }
-        self.arm()
     }
 
-    pub fn arm(&self) {//要改动
-        let ebreak = unsafe { from_raw_parts(__ebreak as *const u8, self.length) };
-        unsafe{
-            os_copy_to_user(self.addr, &(ebreak[0]), self.length);
+    fn add_uprobepoint(&mut self, pid: usize) -> Result<(), UprobeError> {
+        self.prepare(pid)?;
+        if self.has_enabled_consumers() {
+            self.arm(pid)?;
+        }
+        Ok(())
+    }
+
+    /// Read and check the probed instruction in process `pid`. Done once, for all processes
+    /// running the executable.
+    fn prepare(&mut self, pid: usize) -> Result<(), UprobeError> {
+        if self.length != 0 {
+            return Ok(());
+        }
+        let addr = self.addr;
+        // read the lowest byte of the probed instruction to determine whether it is compressed
+        let mut insn = [0u8; 4];
+        read_text(pid, addr, &mut insn[..2])?;
+        let length = get_insn_length([insn[0], insn[1]]);//此处已经修复。
+        if length != 2 && length != 4 {
+            return Err(UprobeError::IllegalInstruction);
+        }
+        if length == 4 {
+            read_text(pid, addr + 2, &mut insn[2..])?;
+        }
+        // somebody else's, which we could neither step nor put back once they take it out
+        if is_ebreak(insn, length) {
+            return Err(UprobeError::ExistingBreakpoint);
+        }
+        // check the probed instruction before touching the address space
+        // a function probe runs the first instruction of the function like any probed instruction
+        self.emulate = match insn_decode(insn){
+            InsnStatus::Illegal => return Err(UprobeError::IllegalInstruction),
+            InsnStatus::Emulate(insn) => Some(insn),
+            InsnStatus::Legal => None,
+        };
+        self.insn = insn;
+        self.length = length;//无需改动。
+        Ok(())
+    }
+
+    /// Put the breakpoint in place in process `pid`, over the instruction read by `prepare`
+    /// and nothing else.
+    pub fn arm(&self, pid: usize) -> Result<(), UprobeError> {
+        let mut insn = [0u8; 4];
+        read_text(pid, self.addr, &mut insn[..self.length])?;
+        if insn[..self.length] != self.insn[..self.length] {
+            return Err(if is_ebreak(insn, self.length) {
+                UprobeError::ExistingBreakpoint
+            } else {
+                UprobeError::InstructionMismatch
+            });
         }
-        // let mut inst = unsafe { from_raw_parts_mut(self.addr as *mut u8, self.length) };
-        // inst.copy_from_slice(ebreak);
-        unsafe { asm!("fence.i") };
+        write_text(pid, self.addr, &EBREAK[..self.length])
     }
 
-    pub fn disarm(&self) {//要改动
-        unsafe{
-            os_copy_to_user(self.addr, self.slot_addr as *const u8, self.length);
+    /// Put the probed instruction back in process `pid`, unless something else replaced
+    /// the breakpoint since.
+    pub fn disarm(&self, pid: usize) -> Result<(), UprobeError> {
+        let mut insn = [0u8; 4];
+        read_text(pid, self.addr, &mut insn[..self.length])?;
+        if insn[..self.length] != EBREAK[..self.length] {
+            return Ok(());
         }
-        // let mut inst = unsafe { from_raw_parts_mut(self.addr as *mut u8, self.length) };
-        // let slot = unsafe { from_raw_parts(self.slot_addr as *const u8, self.length)};
-        // inst.copy_from_slice(slot);
-        unsafe { asm!("fence.i") };
+        write_text(pid, self.addr, &self.insn[..self.length])
     }
 }
 
 impl Uprobes {
     pub fn register_uprobe(
-        &self,
+        &mut self,
         addr: usize,
-        handler: Arc<Mutex<for<'r> fn(&'r mut TrapContext,usize) >>, //tag: uprobe_handler
-        post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>>,
+        mut consumer: UprobeConsumer,
         probe_type: ProbeType,
-    ) -> isize{
-        let probe = UprobesInner::new(addr, handler, post_handler, probe_type);
-        if let Some(probe) = probe {
-            self.inner.borrow_mut().insert(addr, probe);
-            info!("uprobes: register success");
-            1
-        } else {
-            error!("uprobes: probe initialization failed");
-            -1
+    ) -> Result<usize, UprobeError>{
+        let probe = self.inner.entry(addr).or_insert_with(|| UprobesInner::new(addr, probe_type));
+        // consumers share the breakpoint, which is handled differently for each probe type
+        if probe.probe_type != probe_type {
+            return Err(UprobeError::ProbeTypeMismatch);
         }
+        let id = NEXT_PROBE_ID.fetch_add(1, Ordering::Relaxed);
+        consumer.id = id;
+        probe.consumers.push(consumer);
+        info!("uprobes: register success");
+        Ok(id)
+    }
+
+    pub fn unregister_uprobe(&mut self, addr: usize) -> Option<UprobesInner> {
+        self.inner.remove(&addr)
     }
 
     fn new() -> Self {
         Self {
-            inner: RefCell::new(BTreeMap::new()),
+            inner: BTreeMap::new(),
         }
     }
+}
 
-    fn uprobes_trap_handler(&self, cx: &mut UserContext) {
+pub fn uprobe_register(
+    path: String,
+    addr: usize,
+    handler: UprobeHandler, //tag: uprobe_handler
+    post_handler: Option<UprobePostHandler>,
+    probe_type: ProbeType
+) -> Result<ProbeId, UprobeError> {
+    let consumer = UprobeConsumer { handler: Some(handler), post_handler, ..UprobeConsumer::new() };
+    CURRENT_PROCESS_UPROBES.register(path, addr, consumer, probe_type)
+}
 
+/// Probe the function at `addr` of `path` for its return, like a kretprobe.
+///
+/// `entry_handler` runs when the function is entered and may stash up to `data_size` bytes
+/// in the [`UretprobeInstance`] of the call, e.g. a timestamp. `handler` gets the same instance
+/// when that call returns, with the return value in `a0`/`a1` of the trap context. A consumer
+/// disabled in between still sees the return of calls it saw entering.
+pub fn uretprobe_register(
+    path: String,
+    addr: usize,
+    entry_handler: Option<UretprobeEntryHandler>,
+    handler: UretprobeHandler,
+    data_size: usize,
+) -> Result<ProbeId, UprobeError> {
+    if data_size > UPROBE_MAX_DATA_SIZE {
+        return Err(UprobeError::DataTooLarge);
     }
+    let uretprobe = UretprobeConsumer { entry_handler, handler, data_size };
+    let consumer = UprobeConsumer { uretprobe: Some(uretprobe), ..UprobeConsumer::new() };
+    CURRENT_PROCESS_UPROBES.register(path, addr, consumer, ProbeType::SyncFunc)
+}
 
-    fn add_uprobepoint(&self){
-        let mut uproebs = self.inner.borrow_mut();
-        for inner in uproebs.values_mut(){
-            unsafe { inner.add_uprobepoint() };
-        }
+/// Probe the `poll` function of a future at `addr` of `path`. `handler` gets an [`AsyncEvent`]
+/// for the first poll of every future, each later poll and when `poll` returns `Ready`,
+/// keyed by the `self` pointer as `abi` describes. Futures dropped before they are ready are
+/// only forgotten with [`uprobe_register_async_drop`], or else when the probe is unregistered.
+/// Until then a new future at the address of a dropped one is taken for it and gets `Resume`.
+pub fn uprobe_register_async(path: String, addr: usize, handler: AsyncHandler, abi: PollAbi) -> Result<ProbeId, UprobeError> {
+    if abi.self_arg >= 8 {
+        return Err(UprobeError::Unsupported);
     }
+    let consumer = UprobeConsumer { asynchronous: Some((handler, abi)), ..UprobeConsumer::new() };
+    CURRENT_PROCESS_UPROBES.register(path, addr, consumer, ProbeType::AsyncFunc)
 }
 
-#[cfg(feature = "rCore-Plus")]
-fn get_new_page(addr: usize, len: usize) -> usize{
-    let thread = current_thread().unwrap();
-    let mut vm = thread.vm.lock();
-    let ebreak_addr = vm.find_free_area(addr, len);
-    vm.push(
-        ebreak_addr,
-        ebreak_addr + len,
-        MemoryAttr::default().user().execute().writable(),
-        ByFrame::new(GlobalFrameAlloc),
-        "point",
-    );
-    unsafe {asm!("fence.i");}
-    ebreak_addr
+/// Follow the futures of the async probe `poll` up to their drop glue at `addr` of `path`, e.g.
+/// `core::ptr::drop_in_place::<F>` for the future type `F`, which gets the future's address in `a0`.
+/// A future dropped before it is ready gets [`AsyncEvent::Dropped`] and is forgotten.
+pub fn uprobe_register_async_drop(path: String, addr: usize, poll: &ProbeId) -> Result<ProbeId, UprobeError> {
+    let on_event = CURRENT_PROCESS_UPROBES.async_handler(poll).ok_or(UprobeError::NotRegistered)?;
+    let id = poll.id;
+    let handler: UprobeHandler = Arc::new(Mutex::new(move |cx: &mut TrapContext, _addr: usize| {
+        // futures that were ready are gone already
+        if let Some(future) = CURRENT_PROCESS_UPROBES.forget_future(id, cx.x[10]) {
+            (on_event.lock())(cx, AsyncEvent::Dropped, &future);
+        }
+    }));
+    uprobe_register(path, addr, handler, None, ProbeType::Insn)
 }
 
-#[cfg(feature = "rCore-Plus")]
-fn set_writeable(addr: usize){
-        let thread = current_thread().unwrap();
-        let mut vm = thread.vm.lock();
-        let mut page_table_entry = vm.get_page_table_mut().get_entry(addr).unwrap();
-        page_table_entry.set_writable(true);
-        unsafe {asm!("fence.i");}
+/// Time every call of the function at `addr` of `path` with [`OsInterface::current_time`](crate::OsInterface::current_time).
+/// The durations are collected in a histogram read with [`ProbeId::latency`].
+pub fn uprobe_register_latency(path: String, addr: usize) -> Result<ProbeId, UprobeError> {
+    // fail here rather than on every call
+    os().current_time()?;
+    let histogram = Arc::new(Mutex::new(LatencyHistogram::new()));
+    let entry_handler: UretprobeEntryHandler = Arc::new(Mutex::new(|_cx: &mut TrapContext, ri: &mut UretprobeInstance| {
+        match os().current_time() {
+            Ok(start) => {
+                ri.data_mut().copy_from_slice(&start.to_le_bytes());
+                true
+            }
+            Err(_) => false,
+        }
+    }));
+    let returns = histogram.clone();
+    let handler: UretprobeHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, ri: &UretprobeInstance| {
+        let mut start = [0; 8];
+        start.copy_from_slice(ri.data());
+        if let Ok(end) = os().current_time() {
+            returns.lock().record(end.saturating_sub(u64::from_le_bytes(start)));
+        }
+    }));
+    let uretprobe = UretprobeConsumer { entry_handler: Some(entry_handler), handler, data_size: 8 };
+    let consumer = UprobeConsumer { uretprobe: Some(uretprobe), latency: Some(histogram), ..UprobeConsumer::new() };
+    CURRENT_PROCESS_UPROBES.register(path, addr, consumer, ProbeType::SyncFunc)
 }
 
+/// Like [`uprobe_register`], but both handlers also get `data`, which is owned by the probe.
+///
+/// Useful to carry e.g. an attached program id, counters or configuration with each
+/// registration instead of looking them up by address on every hit.
+pub fn uprobe_register_with_data<T: Send + 'static>(
+    path: String,
+    addr: usize,
+    data: T,
+    handler: fn(&mut TrapContext, usize, &mut T),
+    post_handler: Option<fn(&mut TrapContext, &mut T)>,
+    probe_type: ProbeType
+) -> Result<ProbeId, UprobeError> {
+    let data = Arc::new(Mutex::new(data));
+    let pre_data = data.clone();
+    let handler: UprobeHandler = Arc::new(Mutex::new(move |cx: &mut TrapContext, addr: usize| {
+        handler(cx, addr, &mut pre_data.lock())
+    }));
+    let post_handler = post_handler.map(|post_handler| -> UprobePostHandler {
+        Arc::new(Mutex::new(move |cx: &mut TrapContext| post_handler(cx, &mut data.lock())))
+    });
+    uprobe_register(path, addr, handler, post_handler, probe_type)
+}
 
-#[cfg(feature = "rCore-Plus")]
-fn get_exec_path() -> String{
-    info!("uprobes: get path");
-    // get path of current thread
-    let ret = current_thread().unwrap().proc.try_lock().expect("locked!").exec_path.clone();
-    info!("uprobes get path success path = {}", ret);
-    ret
+/// Like [`uprobe_register`], but the probe is placed at `symbol` of the ELF file at `path`,
+/// written as `name` or `name+offset`. The resolved address is [`ProbeId::addr`].
+pub fn uprobe_register_symbol(
+    path: String,
+    symbol: &str,
+    handler: UprobeHandler, //tag: uprobe_handler
+    post_handler: Option<UprobePostHandler>,
+    probe_type: ProbeType
+) -> Result<ProbeId, UprobeError> {
+    let addr = crate::elf::resolve_symbol(&path, symbol)?;
+    uprobe_register(path, addr, handler, post_handler, probe_type)
 }
 
+/// Removes the probe at `addr` of `path` with all of its consumers, restoring the original
+/// instruction in every process it is armed in.
+pub fn uprobe_unregister(path: String, addr: usize) -> Result<(), UprobeError> {
+    CURRENT_PROCESS_UPROBES.unregister_uprobes(os().file_id(&path)?, addr, None)
+}
 
+impl UprobeConsumer {
+    /// An enabled consumer without any handler, the id is given at registration.
+    fn new() -> Self {
+        Self {
+            id: 0,
+            handler: None,
+            post_handler: None,
+            uretprobe: None,
+            latency: None,
+            asynchronous: None,
+            enabled: true,
+        }
+    }
+}
 
-pub fn uprobe_register(
-    path: String,
-    addr: usize,
-    handler: Arc<Mutex<for<'r> fn(&'r mut TrapContext,usize) >>,//tag: uprobe_handler
-    post_handler: Option<Arc<Mutex<dyn FnMut(&mut TrapContext) + Send>>>,
-    probe_type: ProbeType
-) -> isize {
-    CURRENT_PROCESS_UPROBES.register_uprobes(path ,addr, handler, post_handler, probe_type)
+impl UretprobeInstance {
+    fn new(func: usize, trap_context: &TrapContext, data_size: usize) -> Self {
+        let mut args = [0; 8];
+        args.copy_from_slice(&trap_context.x[10..18]);
+        Self {
+            func,
+            ret_addr: trap_context.x[1],
+            args,
+            data_size,
+            data: [0; UPROBE_MAX_DATA_SIZE],
+        }
+    }
+
+    /// The address of the probed function.
+    pub fn func(&self) -> usize {
+        self.func
+    }
+
+    /// Where the call returns to.
+    pub fn ret_addr(&self) -> usize {
+        self.ret_addr
+    }
+
+    /// `a0` to `a7` at the entry of the function.
+    pub fn args(&self) -> &[usize; 8] {
+        &self.args
+    }
+
+    /// The `data_size` bytes given at registration, zeroed at the entry of every call.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.data_size]
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.data_size]
+    }
 }
 
-pub fn uprobes_trap_handler(cx: &mut TrapContext) {
-    info!("uprobes: into uprobes trap handler");
-    unsafe{
-        CURRENT_PROCESS_UPROBES.uprobes_trap_handler(cx);
+impl AsyncFuture {
+    /// The `self` pointer of the future, which tells futures apart.
+    pub fn self_ptr(&self) -> usize {
+        self.self_ptr
+    }
+
+    /// How many times `poll` was entered for the future, including the current call.
+    pub fn polls(&self) -> usize {
+        self.polls
+    }
+
+    /// [`OsInterface::current_time`](crate::OsInterface::current_time) at the first poll, if the OS has a clock.
+    pub fn first_poll_time(&self) -> Option<u64> {
+        self.first_poll_time
+    }
+}
+
+impl Default for PollAbi {
+    /// `self` in `a0` and `Poll<T>` returned in registers, where `Ready` is discriminant 0 in `a0`.
+    /// This holds for `Poll<()>` and for `T` without a niche that fits in `a1`.
+    fn default() -> Self {
+        Self {
+            self_arg: 0,
+            is_ready: |cx, _| cx.x[10] == 0,
+        }
+    }
+}
+
+impl ProbeId {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Call the handlers again after [`disable`](Self::disable).
+    pub fn enable(&self) -> Result<(), UprobeError> {
+        CURRENT_PROCESS_UPROBES.set_enabled(self, true)
+    }
+
+    /// Stop calling the handlers but keep them and the decoded instruction, so that
+    /// [`enable`](Self::enable) is cheap. The original instruction is restored
+    /// when no other consumer at the address is enabled.
+    pub fn disable(&self) -> Result<(), UprobeError> {
+        CURRENT_PROCESS_UPROBES.set_enabled(self, false)
+    }
+
+    /// Whether the consumer is registered and enabled, i.e. called whenever the probe is hit.
+    pub fn is_armed(&self) -> bool {
+        CURRENT_PROCESS_UPROBES.is_enabled(self)
+    }
+
+    /// Choose what a forked child gets of the probe at this address, for all of its consumers.
+    pub fn set_fork_policy(&self, fork_policy: ForkPolicy) -> Result<(), UprobeError> {
+        CURRENT_PROCESS_UPROBES.set_fork_policy(self, fork_policy)
+    }
+
+    /// What a probe from [`uprobe_register_latency`] measured so far,
+    /// `None` for other probes or once it is unregistered.
+    pub fn latency(&self) -> Option<LatencyHistogram> {
+        CURRENT_PROCESS_UPROBES.latency(self)
+    }
+
+    /// Remove this consumer only. The breakpoint is removed as in [`uprobe_unregister`]
+    /// once no consumer is left.
+    pub fn unregister(self) -> Result<(), UprobeError> {
+        CURRENT_PROCESS_UPROBES.unregister_uprobes(self.file, self.addr, Some(self.id))
     }
 }
 
+/// Call on every breakpoint trap from user mode. Genuine breakpoints of the program, reported
+/// as [`UprobeTrapResult::NotOurs`], are left for the OS to deliver to the process or its debugger.
+pub fn uprobes_trap_handler(cx: &mut TrapContext) -> UprobeTrapResult {
+    info!("uprobes: into uprobes trap handler");
+    CURRENT_PROCESS_UPROBES.uprobes_trap_handler(cx)
+}
+
+/// Call from the forking thread of process `parent` once `child` has its copy of the address
+/// space, with `child_tid` the id its thread will have. The child shares the probes of the
+/// parent; what it gets of each of them is chosen with [`ProbeId::set_fork_policy`].
+pub fn uprobes_fork(parent: usize, child: usize, child_tid: usize) -> Result<(), UprobeError> {
+    CURRENT_PROCESS_UPROBES.uprobes_fork(parent, child, child_tid)
+}
+
+/// Call when the current process exits, once none of its threads runs user code anymore.
+pub fn uprobes_exit() {
+    CURRENT_PROCESS_UPROBES.uprobes_exit();
+}
+
+/// Call when thread `tid` of the current process exits while others go on, once it no longer
+/// runs user code. Its id may then be given to a new thread.
+pub fn uprobes_thread_exit(tid: usize) {
+    CURRENT_PROCESS_UPROBES.uprobes_thread_exit(tid);
+}
+
 pub fn uprobes_init(){
     CURRENT_PROCESS_UPROBES.uprobes_init();
     info!("uprobes: init sucess");
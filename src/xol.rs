@@ -0,0 +1,74 @@
+//! The out-of-line execution (XOL) area of a process.
+//!
+//! A single region is mapped through [`OsInterface::alloc_xol_page`](crate::OsInterface::alloc_xol_page)
+//! on first use and cut into fixed-size slots, handed out with a bitmap. Probed instructions,
+//! return trampolines and the single step slot of every thread all live there.
+
+use crate::error::UprobeError;
+use crate::os::os;
+
+/// Room for a 4 byte instruction followed by `c.ebreak`, keeping slots 8 byte aligned.
+pub const XOL_SLOT_SIZE: usize = 8;
+
+/// Size of the region mapped for each process.
+pub const XOL_AREA_SIZE: usize = 4 * 4096;
+
+const SLOTS: usize = XOL_AREA_SIZE / XOL_SLOT_SIZE;
+
+#[derive(Clone)]
+pub struct XolArea {
+    base: usize,
+    used: [u64; SLOTS / 64],
+}
+
+impl XolArea {
+    pub const fn new() -> Self {
+        Self {
+            base: 0,
+            used: [0; SLOTS / 64],
+        }
+    }
+
+    /// Take a free slot, mapping the region near `addr` if this is the first one.
+    pub fn alloc_slot(&mut self, addr: usize) -> Result<usize, UprobeError> {
+        if self.base == 0 {
+            self.base = os().alloc_xol_page(addr, XOL_AREA_SIZE)?;
+        }
+        for (i, word) in self.used.iter_mut().enumerate() {
+            if *word != u64::MAX {
+                let bit = (!*word).trailing_zeros() as usize;
+                *word |= 1 << bit;
+                return Ok(self.base + (i * 64 + bit) * XOL_SLOT_SIZE);
+            }
+        }
+        Err(UprobeError::NoFreePage)
+    }
+
+    pub fn free_slot(&mut self, slot_addr: usize) {
+        if slot_addr < self.base || slot_addr >= self.base + XOL_AREA_SIZE {
+            warn!("uprobes: {:#x} is not an XOL slot", slot_addr);
+            return;
+        }
+        let index = (slot_addr - self.base) / XOL_SLOT_SIZE;
+        self.used[index / 64] &= !(1 << (index % 64));
+    }
+
+    /// Unmap the region, freeing every slot at once.
+    pub fn unmap(&mut self) {
+        if self.base != 0 {
+            os().free_xol_page(self.base, XOL_AREA_SIZE);
+        }
+        self.forget();
+    }
+
+    /// Drop the region without unmapping it, when the address space it lived in is gone.
+    pub fn forget(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for XolArea {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,31 @@
+//! A global allocator counting the allocations of the threads that ask for it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Install with `#[global_allocator]` in the test binary to use [`allocations`].
+pub struct CountingAlloc;
+
+std::thread_local! {
+    /// Only counted while [`allocations`] runs on the thread.
+    static COUNT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the thread local is gone while the thread exits
+        let _ = COUNT.try_with(|count| count.set(count.get().map(|n| n + 1)));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Run `f` and give how many times the calling thread allocated in it, reallocations included.
+pub fn allocations(f: impl FnOnce()) -> usize {
+    COUNT.with(|count| count.set(Some(0)));
+    f();
+    COUNT.with(|count| count.take()).unwrap()
+}
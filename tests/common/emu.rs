@@ -0,0 +1,414 @@
+//! A minimal RV64IMC user-mode interpreter running on top of the mock address space.
+//!
+//! Breakpoints (`ebreak` and `c.ebreak`) are handed to `uprobes_trap_handler` the way a
+//! kernel would, and execution continues with the `TrapContext` it leaves behind, so the
+//! whole probe flow including out-of-line single steps and return trampolines runs for real.
+
+use ruprobes::mock::PERM_X;
+use ruprobes::{uprobes_trap_handler, OsInterface, UprobeError, UprobeTrapResult};
+
+use super::{trap_context, OS};
+
+/// Why [`Emulator::run`] stopped.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// The program executed `ecall`, which the tests use as "exit".
+    Ecall,
+    /// A breakpoint that was not ruprobes'.
+    Breakpoint(usize),
+    /// A breakpoint of ruprobes that it failed to handle.
+    Uprobe(UprobeError),
+    /// Instruction fetch, load or store at an inaccessible address.
+    Fault(usize),
+    IllegalInstruction(usize),
+    StepLimit,
+}
+
+pub struct Emulator {
+    pub x: [usize; 32],
+    pub pc: usize,
+    /// Breakpoint traps handled by ruprobes.
+    pub traps: usize,
+    pub steps: usize,
+}
+
+fn sext(value: u64, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64) >> shift
+}
+
+impl Emulator {
+    pub fn new(pc: usize, sp: usize) -> Self {
+        let mut x = [0; 32];
+        x[2] = sp;
+        Self { x, pc, traps: 0, steps: 0 }
+    }
+
+    /// Run until `ecall` or an unhandled event, executing at most `max_steps` instructions.
+    /// The mock clock ticks once per instruction or trap.
+    pub fn run(&mut self, max_steps: usize) -> Exit {
+        for _ in 0..max_steps {
+            if let Some(exit) = self.step() {
+                return exit;
+            }
+            self.steps += 1;
+            OS.advance_time(1);
+        }
+        Exit::StepLimit
+    }
+
+    fn reg(&self, r: u32) -> u64 {
+        self.x[r as usize] as u64
+    }
+
+    fn set(&mut self, r: u32, value: u64) {
+        if r != 0 {
+            self.x[r as usize] = value as usize;
+        }
+    }
+
+    fn fetch(&self, addr: usize) -> Option<u16> {
+        match OS.perm(addr) {
+            Some(perm) if perm & PERM_X != 0 => {
+                let bytes = OS.read(addr, 2);
+                Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+            }
+            _ => None,
+        }
+    }
+
+    fn load(&self, addr: u64, size: usize, signed: bool) -> Result<u64, Exit> {
+        let mut buf = [0u8; 8];
+        OS.copy_from_user(addr as usize, &mut buf[..size]).map_err(|_| Exit::Fault(addr as usize))?;
+        let value = u64::from_le_bytes(buf);
+        Ok(if signed { sext(value, size as u32 * 8) as u64 } else { value })
+    }
+
+    fn store(&self, addr: u64, size: usize, value: u64) -> Result<(), Exit> {
+        OS.copy_to_user(addr as usize, &value.to_le_bytes()[..size]).map_err(|_| Exit::Fault(addr as usize))
+    }
+
+    fn breakpoint(&mut self) -> Option<Exit> {
+        let pc = self.pc;
+        let mut cx = trap_context(pc);
+        cx.x = self.x;
+        match uprobes_trap_handler(&mut cx) {
+            UprobeTrapResult::Handled => {
+                self.x = cx.x;
+                self.x[0] = 0;
+                self.pc = cx.sepc;
+                self.traps += 1;
+                None
+            }
+            UprobeTrapResult::NotOurs => Some(Exit::Breakpoint(pc)),
+            UprobeTrapResult::Error(err) => Some(Exit::Uprobe(err)),
+        }
+    }
+
+    fn step(&mut self) -> Option<Exit> {
+        let pc = self.pc;
+        let low = match self.fetch(pc) {
+            Some(low) => low,
+            None => return Some(Exit::Fault(pc)),
+        };
+        let result = if low & 0b11 != 0b11 {
+            self.exec_compressed(low)
+        } else {
+            let high = match self.fetch(pc + 2) {
+                Some(high) => high,
+                None => return Some(Exit::Fault(pc + 2)),
+            };
+            self.exec((high as u32) << 16 | low as u32)
+        };
+        match result {
+            Ok(next) => {
+                self.pc = next;
+                None
+            }
+            Err(Exit::Breakpoint(_)) => self.breakpoint(),
+            Err(exit) => Some(exit),
+        }
+    }
+
+    fn exec(&mut self, insn: u32) -> Result<usize, Exit> {
+        let pc = self.pc as u64;
+        let next = pc + 4;
+        let opcode = insn & 0x7f;
+        let rd = (insn >> 7) & 31;
+        let funct3 = (insn >> 12) & 7;
+        let rs1 = (insn >> 15) & 31;
+        let rs2 = (insn >> 20) & 31;
+        let funct7 = insn >> 25;
+        let imm_i = (insn as i32 >> 20) as i64 as u64;
+        let imm_s = (((insn as i32 >> 25) << 5) | ((insn >> 7) & 0x1f) as i32) as i64 as u64;
+        let imm_b = sext(
+            (((insn >> 31) & 1) << 12 | ((insn >> 7) & 1) << 11 | ((insn >> 25) & 0x3f) << 5 | ((insn >> 8) & 0xf) << 1) as u64,
+            13,
+        ) as u64;
+        let imm_u = (insn & 0xffff_f000) as i32 as i64 as u64;
+        let imm_j = sext(
+            (((insn >> 31) & 1) << 20 | ((insn >> 12) & 0xff) << 12 | ((insn >> 20) & 1) << 11 | ((insn >> 21) & 0x3ff) << 1) as u64,
+            21,
+        ) as u64;
+        let illegal = Exit::IllegalInstruction(self.pc);
+        let (a, b) = (self.reg(rs1), self.reg(rs2));
+        match opcode {
+            0x37 => self.set(rd, imm_u),
+            0x17 => self.set(rd, pc.wrapping_add(imm_u)),
+            0x6f => {
+                self.set(rd, next);
+                return Ok(pc.wrapping_add(imm_j) as usize);
+            }
+            0x67 => {
+                let target = a.wrapping_add(imm_i) & !1;
+                self.set(rd, next);
+                return Ok(target as usize);
+            }
+            0x63 => {
+                let taken = match funct3 {
+                    0 => a == b,
+                    1 => a != b,
+                    4 => (a as i64) < (b as i64),
+                    5 => (a as i64) >= (b as i64),
+                    6 => a < b,
+                    7 => a >= b,
+                    _ => return Err(illegal),
+                };
+                if taken {
+                    return Ok(pc.wrapping_add(imm_b) as usize);
+                }
+            }
+            0x03 => {
+                let addr = a.wrapping_add(imm_i);
+                let value = match funct3 {
+                    0 => self.load(addr, 1, true)?,
+                    1 => self.load(addr, 2, true)?,
+                    2 => self.load(addr, 4, true)?,
+                    3 => self.load(addr, 8, false)?,
+                    4 => self.load(addr, 1, false)?,
+                    5 => self.load(addr, 2, false)?,
+                    6 => self.load(addr, 4, false)?,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value);
+            }
+            0x23 => {
+                let addr = a.wrapping_add(imm_s);
+                match funct3 {
+                    0 => self.store(addr, 1, b)?,
+                    1 => self.store(addr, 2, b)?,
+                    2 => self.store(addr, 4, b)?,
+                    3 => self.store(addr, 8, b)?,
+                    _ => return Err(illegal),
+                }
+            }
+            0x13 => {
+                let shamt = (imm_i & 0x3f) as u32;
+                let value = match funct3 {
+                    0 => a.wrapping_add(imm_i),
+                    1 => a << shamt,
+                    2 => ((a as i64) < (imm_i as i64)) as u64,
+                    3 => (a < imm_i) as u64,
+                    4 => a ^ imm_i,
+                    5 if funct7 >> 1 == 0x10 => ((a as i64) >> shamt) as u64,
+                    5 => a >> shamt,
+                    6 => a | imm_i,
+                    7 => a & imm_i,
+                    _ => unreachable!(),
+                };
+                self.set(rd, value);
+            }
+            0x1b => {
+                let shamt = (imm_i & 0x1f) as u32;
+                let value = match funct3 {
+                    0 => a.wrapping_add(imm_i) as u32,
+                    1 => (a as u32) << shamt,
+                    5 if funct7 == 0x20 => ((a as i32) >> shamt) as u32,
+                    5 => (a as u32) >> shamt,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value as i32 as i64 as u64);
+            }
+            0x33 => {
+                let value = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << (b & 0x3f),
+                    (0, 2) => ((a as i64) < (b as i64)) as u64,
+                    (0, 3) => (a < b) as u64,
+                    (0, 4) => a ^ b,
+                    (0, 5) => a >> (b & 0x3f),
+                    (0x20, 5) => ((a as i64) >> (b & 0x3f)) as u64,
+                    (0, 6) => a | b,
+                    (0, 7) => a & b,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 1) => ((a as i64 as i128 * b as i64 as i128) >> 64) as u64,
+                    (1, 2) => ((a as i64 as i128 * b as u128 as i128) >> 64) as u64,
+                    (1, 3) => ((a as u128 * b as u128) >> 64) as u64,
+                    (1, 4) if b == 0 => u64::MAX,
+                    (1, 4) => (a as i64).wrapping_div(b as i64) as u64,
+                    (1, 5) if b == 0 => u64::MAX,
+                    (1, 5) => a / b,
+                    (1, 6) if b == 0 => a,
+                    (1, 6) => (a as i64).wrapping_rem(b as i64) as u64,
+                    (1, 7) if b == 0 => a,
+                    (1, 7) => a % b,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value);
+            }
+            0x3b => {
+                let (a, b) = (a as u32, b as u32);
+                let value = match (funct7, funct3) {
+                    (0, 0) => a.wrapping_add(b),
+                    (0x20, 0) => a.wrapping_sub(b),
+                    (0, 1) => a << (b & 0x1f),
+                    (0, 5) => a >> (b & 0x1f),
+                    (0x20, 5) => ((a as i32) >> (b & 0x1f)) as u32,
+                    (1, 0) => a.wrapping_mul(b),
+                    (1, 4) if b == 0 => u32::MAX,
+                    (1, 4) => (a as i32).wrapping_div(b as i32) as u32,
+                    (1, 5) if b == 0 => u32::MAX,
+                    (1, 5) => a / b,
+                    (1, 6) if b == 0 => a,
+                    (1, 6) => (a as i32).wrapping_rem(b as i32) as u32,
+                    (1, 7) if b == 0 => a,
+                    (1, 7) => a % b,
+                    _ => return Err(illegal),
+                };
+                self.set(rd, value as i32 as i64 as u64);
+            }
+            0x0f => {}
+            0x73 => match insn {
+                0x0000_0073 => return Err(Exit::Ecall),
+                0x0010_0073 => return Err(Exit::Breakpoint(self.pc)),
+                _ => return Err(illegal),
+            },
+            _ => return Err(illegal),
+        }
+        Ok(next as usize)
+    }
+
+    fn exec_compressed(&mut self, insn: u16) -> Result<usize, Exit> {
+        let i = insn as u32;
+        let pc = self.pc as u64;
+        let next = pc + 2;
+        let illegal = Exit::IllegalInstruction(self.pc);
+        let rd = (i >> 7) & 31;
+        let rs2 = (i >> 2) & 31;
+        // registers x8..x15 of the 3 bit fields
+        let rd_ = ((i >> 2) & 7) + 8;
+        let rs1_ = ((i >> 7) & 7) + 8;
+        let imm6 = sext((((i >> 12) & 1) << 5 | (i >> 2) & 31) as u64, 6) as u64;
+        let uimm_w = ((i >> 10) & 7) << 3 | ((i >> 6) & 1) << 2 | ((i >> 5) & 1) << 6;
+        let uimm_d = ((i >> 10) & 7) << 3 | ((i >> 5) & 3) << 6;
+        match (i & 0b11, i >> 13) {
+            (0b00, 0b000) => {
+                let nzuimm = ((i >> 7) & 0xf) << 6 | ((i >> 11) & 3) << 4 | ((i >> 5) & 1) << 3 | ((i >> 6) & 1) << 2;
+                if nzuimm == 0 {
+                    return Err(illegal);
+                }
+                self.set(rd_, self.reg(2).wrapping_add(nzuimm as u64));
+            }
+            (0b00, 0b010) => {
+                let value = self.load(self.reg(rs1_).wrapping_add(uimm_w as u64), 4, true)?;
+                self.set(rd_, value);
+            }
+            (0b00, 0b011) => {
+                let value = self.load(self.reg(rs1_).wrapping_add(uimm_d as u64), 8, false)?;
+                self.set(rd_, value);
+            }
+            (0b00, 0b110) => self.store(self.reg(rs1_).wrapping_add(uimm_w as u64), 4, self.reg(rd_))?,
+            (0b00, 0b111) => self.store(self.reg(rs1_).wrapping_add(uimm_d as u64), 8, self.reg(rd_))?,
+            (0b01, 0b000) => self.set(rd, self.reg(rd).wrapping_add(imm6)),
+            (0b01, 0b001) => self.set(rd, self.reg(rd).wrapping_add(imm6) as u32 as i32 as i64 as u64),
+            (0b01, 0b010) => self.set(rd, imm6),
+            (0b01, 0b011) if rd == 2 => {
+                let imm = sext(
+                    (((i >> 12) & 1) << 9 | ((i >> 6) & 1) << 4 | ((i >> 5) & 1) << 6 | ((i >> 3) & 3) << 7 | ((i >> 2) & 1) << 5) as u64,
+                    10,
+                ) as u64;
+                self.set(2, self.reg(2).wrapping_add(imm));
+            }
+            (0b01, 0b011) => self.set(rd, imm6 << 12),
+            (0b01, 0b100) => {
+                let a = self.reg(rs1_);
+                let b = self.reg(rd_);
+                let shamt = ((i >> 12) & 1) << 5 | (i >> 2) & 31;
+                let value = match ((i >> 10) & 3, (i >> 12) & 1, (i >> 5) & 3) {
+                    (0b00, _, _) => a >> shamt,
+                    (0b01, _, _) => ((a as i64) >> shamt) as u64,
+                    (0b10, _, _) => a & imm6,
+                    (0b11, 0, 0b00) => a.wrapping_sub(b),
+                    (0b11, 0, 0b01) => a ^ b,
+                    (0b11, 0, 0b10) => a | b,
+                    (0b11, 0, 0b11) => a & b,
+                    (0b11, 1, 0b00) => (a as u32).wrapping_sub(b as u32) as i32 as i64 as u64,
+                    (0b11, 1, 0b01) => (a as u32).wrapping_add(b as u32) as i32 as i64 as u64,
+                    _ => return Err(illegal),
+                };
+                self.set(rs1_, value);
+            }
+            (0b01, 0b101) => {
+                let offset = sext(
+                    (((i >> 12) & 1) << 11
+                        | ((i >> 11) & 1) << 4
+                        | ((i >> 9) & 3) << 8
+                        | ((i >> 8) & 1) << 10
+                        | ((i >> 7) & 1) << 6
+                        | ((i >> 6) & 1) << 7
+                        | ((i >> 3) & 7) << 1
+                        | ((i >> 2) & 1) << 5) as u64,
+                    12,
+                ) as u64;
+                return Ok(pc.wrapping_add(offset) as usize);
+            }
+            (0b01, 0b110) | (0b01, 0b111) => {
+                let offset = sext(
+                    (((i >> 12) & 1) << 8 | ((i >> 10) & 3) << 3 | ((i >> 5) & 3) << 6 | ((i >> 3) & 3) << 1 | ((i >> 2) & 1) << 5) as u64,
+                    9,
+                ) as u64;
+                let zero = self.reg(rs1_) == 0;
+                if zero == (i >> 13 == 0b110) {
+                    return Ok(pc.wrapping_add(offset) as usize);
+                }
+            }
+            (0b10, 0b000) => {
+                let shamt = ((i >> 12) & 1) << 5 | (i >> 2) & 31;
+                self.set(rd, self.reg(rd) << shamt);
+            }
+            (0b10, 0b010) => {
+                let uimm = ((i >> 12) & 1) << 5 | ((i >> 4) & 7) << 2 | ((i >> 2) & 3) << 6;
+                let value = self.load(self.reg(2).wrapping_add(uimm as u64), 4, true)?;
+                self.set(rd, value);
+            }
+            (0b10, 0b011) => {
+                let uimm = ((i >> 12) & 1) << 5 | ((i >> 5) & 3) << 3 | ((i >> 2) & 7) << 6;
+                let value = self.load(self.reg(2).wrapping_add(uimm as u64), 8, false)?;
+                self.set(rd, value);
+            }
+            (0b10, 0b100) => match ((i >> 12) & 1, rd, rs2) {
+                (0, 0, 0) => return Err(illegal),
+                (0, _, 0) => return Ok((self.reg(rd) & !1) as usize),
+                (0, _, _) => self.set(rd, self.reg(rs2)),
+                (1, 0, 0) => return Err(Exit::Breakpoint(self.pc)),
+                (1, _, 0) => {
+                    let target = self.reg(rd) & !1;
+                    self.set(1, next);
+                    return Ok(target as usize);
+                }
+                _ => self.set(rd, self.reg(rd).wrapping_add(self.reg(rs2))),
+            },
+            (0b10, 0b110) => {
+                let uimm = ((i >> 9) & 0xf) << 2 | ((i >> 7) & 3) << 6;
+                self.store(self.reg(2).wrapping_add(uimm as u64), 4, self.reg(rs2))?;
+            }
+            (0b10, 0b111) => {
+                let uimm = ((i >> 10) & 7) << 3 | ((i >> 7) & 7) << 6;
+                self.store(self.reg(2).wrapping_add(uimm as u64), 8, self.reg(rs2))?;
+            }
+            _ => return Err(illegal),
+        }
+        Ok(next as usize)
+    }
+}
@@ -0,0 +1,56 @@
+//! Probe hits driven straight through the trap handler, the way the hot path benchmark and
+//! the allocation test repeat them.
+
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use ruprobes::{uprobe_register, uprobes_trap_handler, uretprobe_register, ProbeType, TrapContext, UprobeHandler, UprobeTrapResult, UretprobeHandler, UretprobeInstance};
+
+use super::{asm, load_text, trap_context, u32_bytes, TEXT};
+
+/// Where the probed function returns to.
+const CALLER: usize = TEXT + 0x100;
+
+fn ignore_hit(_cx: &mut TrapContext, _addr: usize) {}
+
+fn ignore_return(_cx: &mut TrapContext, _ri: &UretprobeInstance) {}
+
+/// Load a text with an instruction probe that is single stepped, one that is emulated and a
+/// function with a return probe, in the executable at `path`.
+pub fn register(path: &str) {
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::auipc(asm::A1, 0), asm::NOP, asm::RET]));
+    let handler: UprobeHandler = Arc::new(Mutex::new(ignore_hit));
+    uprobe_register(path.into(), TEXT, handler.clone(), None, ProbeType::Insn).unwrap();
+    uprobe_register(path.into(), TEXT + 4, handler, None, ProbeType::Insn).unwrap();
+    let handler: UretprobeHandler = Arc::new(Mutex::new(ignore_return));
+    uretprobe_register(path.into(), TEXT + 8, None, handler, 0).unwrap();
+}
+
+/// Hit the single stepped probe and the `c.ebreak` after its slot.
+pub fn step() {
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    cx.sepc += 4;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+}
+
+/// Hit the emulated probe.
+pub fn emulate() {
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.x[11], TEXT + 4);
+}
+
+/// Call the function: its entry, the step of its first instruction and its return through the trampoline.
+pub fn call() {
+    let mut cx = trap_context(TEXT + 8);
+    cx.x[1] = CALLER;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    cx.sepc += 4;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    cx.sepc = cx.x[1];
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, CALLER);
+}
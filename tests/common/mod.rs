@@ -0,0 +1,352 @@
+#![allow(dead_code)]
+
+pub mod counting;
+pub mod emu;
+pub mod hits;
+
+use std::sync::{Mutex, MutexGuard};
+
+use ruprobes::mock::{MockOs, PERM_R, PERM_X};
+use ruprobes::{uprobes_init, uprobes_os_init, TrapContext};
+
+pub static OS: MockOs = MockOs::new();
+
+/// Probe tables and the mock are global, so tests touching them run one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Where test programs are loaded.
+pub const TEXT: usize = 0x1_0000;
+
+pub const C_EBREAK: [u8; 2] = [0x02, 0x90];
+
+/// Register the mock and give the calling test a fresh address space running `path`.
+/// Probes are kept by file and outlive the test, so every test should use its own path.
+pub fn setup(path: &str) -> MutexGuard<'static, ()> {
+    let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    uprobes_os_init(&OS);
+    OS.reset();
+    OS.set_exec_path(path);
+    // like an exec, so that process 0 of an earlier test is forgotten
+    uprobes_init();
+    guard
+}
+
+/// Map a text page at [`TEXT`] holding `code`.
+pub fn load_text(code: &[u8]) {
+    OS.map(TEXT, code.len(), PERM_R | PERM_X);
+    OS.write(TEXT, code);
+}
+
+pub fn trap_context(sepc: usize) -> TrapContext {
+    // TrapContext is plain old data, all zeroes is a valid value
+    let mut cx: TrapContext = unsafe { core::mem::zeroed() };
+    cx.sepc = sepc;
+    cx
+}
+
+pub fn u16_bytes(insns: &[u16]) -> Vec<u8> {
+    insns.iter().flat_map(|insn| insn.to_le_bytes()).collect()
+}
+
+pub fn u32_bytes(insns: &[u32]) -> Vec<u8> {
+    insns.iter().flat_map(|insn| insn.to_le_bytes()).collect()
+}
+
+/// Encoders for the RV64IMC instructions the tests are written in.
+pub mod asm {
+    pub const ZERO: u32 = 0;
+    pub const RA: u32 = 1;
+    pub const SP: u32 = 2;
+    pub const T0: u32 = 5;
+    pub const S0: u32 = 8;
+    pub const S1: u32 = 9;
+    pub const A0: u32 = 10;
+    pub const A1: u32 = 11;
+
+    pub const ECALL: u32 = 0x0000_0073;
+    pub const EBREAK: u32 = 0x0010_0073;
+    pub const RET: u32 = 0x0000_8067;
+    pub const NOP: u32 = 0x0000_0013;
+    /// c.addi16sp sp, -64
+    pub const C_ADDI16SP_NEG64: u16 = 0x7139;
+    /// c.ret, i.e. c.jr ra
+    pub const C_RET: u16 = 0x8082;
+    pub const C_EBREAK: u16 = 0x9002;
+
+    fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+    }
+
+    fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+    }
+
+    fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        (((imm >> 5) & 0x7f) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | ((imm & 0x1f) << 7) | opcode
+    }
+
+    fn b_type(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (((imm >> 12) & 1) << 31)
+            | (((imm >> 5) & 0x3f) << 25)
+            | (rs2 << 20)
+            | (rs1 << 15)
+            | (funct3 << 12)
+            | (((imm >> 1) & 0xf) << 8)
+            | (((imm >> 11) & 1) << 7)
+            | 0x63
+    }
+
+    pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i_type(0x13, 0, rd, rs1, imm)
+    }
+
+    pub fn li(rd: u32, imm: i32) -> u32 {
+        addi(rd, ZERO, imm)
+    }
+
+    pub fn mv(rd: u32, rs1: u32) -> u32 {
+        addi(rd, rs1, 0)
+    }
+
+    pub fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r_type(0x33, 0, 0, rd, rs1, rs2)
+    }
+
+    pub fn sub(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r_type(0x33, 0, 0x20, rd, rs1, rs2)
+    }
+
+    pub fn mul(rd: u32, rs1: u32, rs2: u32) -> u32 {
+        r_type(0x33, 0, 1, rd, rs1, rs2)
+    }
+
+    pub fn ld(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i_type(0x03, 3, rd, rs1, imm)
+    }
+
+    pub fn sd(rs2: u32, rs1: u32, imm: i32) -> u32 {
+        s_type(0x23, 3, rs1, rs2, imm)
+    }
+
+    pub fn lui(rd: u32, imm: u32) -> u32 {
+        (imm << 12) | (rd << 7) | 0x37
+    }
+
+    pub fn auipc(rd: u32, imm: u32) -> u32 {
+        (imm << 12) | (rd << 7) | 0x17
+    }
+
+    pub fn jal(rd: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (((imm >> 20) & 1) << 31) | (((imm >> 1) & 0x3ff) << 21) | (((imm >> 11) & 1) << 20) | (((imm >> 12) & 0xff) << 12) | (rd << 7) | 0x6f
+    }
+
+    pub fn jalr(rd: u32, rs1: u32, imm: i32) -> u32 {
+        i_type(0x67, 0, rd, rs1, imm)
+    }
+
+    pub fn beq(rs1: u32, rs2: u32, offset: i32) -> u32 {
+        b_type(0, rs1, rs2, offset)
+    }
+
+    pub fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+        b_type(1, rs1, rs2, offset)
+    }
+
+    pub fn blt(rs1: u32, rs2: u32, offset: i32) -> u32 {
+        b_type(4, rs1, rs2, offset)
+    }
+
+    pub fn bge(rs1: u32, rs2: u32, offset: i32) -> u32 {
+        b_type(5, rs1, rs2, offset)
+    }
+
+    /// c.addi rd, imm
+    pub fn c_addi(rd: u32, imm: i32) -> u16 {
+        let imm = imm as u32;
+        ((((imm >> 5) & 1) << 12) | (rd << 7) | ((imm & 0x1f) << 2) | 0b01) as u16
+    }
+
+    /// c.li rd, imm
+    pub fn c_li(rd: u32, imm: i32) -> u16 {
+        c_addi(rd, imm) | (0b010 << 13)
+    }
+
+    /// c.addi16sp sp, imm
+    pub fn c_addi16sp(imm: i32) -> u16 {
+        let imm = imm as u32;
+        ((0b011 << 13)
+            | (((imm >> 9) & 1) << 12)
+            | (SP << 7)
+            | (((imm >> 4) & 1) << 6)
+            | (((imm >> 6) & 1) << 5)
+            | (((imm >> 7) & 3) << 3)
+            | (((imm >> 5) & 1) << 2)
+            | 0b01) as u16
+    }
+
+    /// c.bnez rs1', offset, `rs1` has to be one of x8..x15
+    pub fn c_bnez(rs1: u32, offset: i32) -> u16 {
+        let imm = offset as u32;
+        ((0b111 << 13)
+            | (((imm >> 8) & 1) << 12)
+            | (((imm >> 3) & 3) << 10)
+            | ((rs1 - 8) << 7)
+            | (((imm >> 6) & 3) << 5)
+            | (((imm >> 1) & 3) << 3)
+            | (((imm >> 5) & 1) << 2)
+            | 0b01) as u16
+    }
+
+    /// c.j offset
+    pub fn c_j(offset: i32) -> u16 {
+        let imm = offset as u32;
+        ((0b101 << 13)
+            | (((imm >> 11) & 1) << 12)
+            | (((imm >> 4) & 1) << 11)
+            | (((imm >> 8) & 3) << 9)
+            | (((imm >> 10) & 1) << 8)
+            | (((imm >> 6) & 1) << 7)
+            | (((imm >> 7) & 1) << 6)
+            | (((imm >> 1) & 7) << 3)
+            | (((imm >> 5) & 1) << 2)
+            | 0b01) as u16
+    }
+
+    /// c.jr rs1
+    pub fn c_jr(rs1: u32) -> u16 {
+        ((0b100 << 13) | (rs1 << 7) | 0b10) as u16
+    }
+
+    /// c.jalr rs1
+    pub fn c_jalr(rs1: u32) -> u16 {
+        ((0b100 << 13) | (1 << 12) | (rs1 << 7) | 0b10) as u16
+    }
+
+    /// c.mv rd, rs2
+    pub fn c_mv(rd: u32, rs2: u32) -> u16 {
+        ((0b100 << 13) | (rd << 7) | (rs2 << 2) | 0b10) as u16
+    }
+
+    /// c.sdsp rs2, uimm(sp)
+    pub fn c_sdsp(rs2: u32, uimm: u32) -> u16 {
+        ((0b111 << 13) | (((uimm >> 3) & 7) << 10) | (((uimm >> 6) & 7) << 7) | (rs2 << 2) | 0b10) as u16
+    }
+
+    /// c.ldsp rd, uimm(sp)
+    pub fn c_ldsp(rd: u32, uimm: u32) -> u16 {
+        ((0b011 << 13) | (((uimm >> 5) & 1) << 12) | (rd << 7) | (((uimm >> 3) & 3) << 5) | (((uimm >> 6) & 7) << 2) | 0b10) as u16
+    }
+}
+
+/// Assembles a mix of normal and compressed instructions and resolves branch targets.
+pub struct Program {
+    pub code: Vec<u8>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self { code: Vec::new() }
+    }
+
+    /// Offset of the next instruction from [`TEXT`].
+    pub fn here(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn addr(&self) -> usize {
+        TEXT + self.here()
+    }
+
+    pub fn push(&mut self, insn: u32) -> usize {
+        let at = self.here();
+        self.code.extend_from_slice(&insn.to_le_bytes());
+        at
+    }
+
+    pub fn push_c(&mut self, insn: u16) -> usize {
+        let at = self.here();
+        self.code.extend_from_slice(&insn.to_le_bytes());
+        at
+    }
+
+    /// Emit a pc-relative instruction whose target is only known later.
+    pub fn push_fixup(&mut self) -> usize {
+        self.push(asm::NOP)
+    }
+
+    /// Fill a slot from `push_fixup` with `encode(target - slot)`.
+    pub fn fixup(&mut self, at: usize, target: usize, encode: impl Fn(i32) -> u32) {
+        let insn = encode(target as i32 - at as i32);
+        self.code[at..at + 4].copy_from_slice(&insn.to_le_bytes());
+    }
+
+    pub fn offset(&self, at: usize, target: usize) -> i32 {
+        target as i32 - at as i32
+    }
+}
+
+/// A function symbol for [`elf_file`].
+pub struct Sym {
+    pub name: &'static str,
+    pub value: usize,
+    pub size: usize,
+}
+
+/// Build a RISC-V ELF64 file holding nothing but `text`, linked at [`TEXT`], and a symbol table
+/// with `syms`, either as `.symtab` (sh_type 2) or `.dynsym` (sh_type 11).
+pub fn elf_file(syms: &[Sym], text: &[u8], sh_type: u32) -> Vec<u8> {
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 24];
+    for sym in syms {
+        symtab.extend_from_slice(&(strtab.len() as u32).to_le_bytes());
+        // STB_GLOBAL, STT_FUNC, defined in section 1
+        symtab.extend_from_slice(&[0x12, 0]);
+        symtab.extend_from_slice(&1u16.to_le_bytes());
+        symtab.extend_from_slice(&(sym.value as u64).to_le_bytes());
+        symtab.extend_from_slice(&(sym.size as u64).to_le_bytes());
+        strtab.extend_from_slice(sym.name.as_bytes());
+        strtab.push(0);
+    }
+    let text_off = 64;
+    let symtab_off = text_off + text.len();
+    let strtab_off = symtab_off + symtab.len();
+    let shoff = strtab_off + strtab.len();
+
+    let mut file = Vec::new();
+    file.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    file.resize(16, 0);
+    file.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    file.extend_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&(TEXT as u64).to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes());
+    file.extend_from_slice(&(shoff as u64).to_le_bytes());
+    file.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 0, 0, 64, 4, 0] {
+        file.extend_from_slice(&half.to_le_bytes());
+    }
+    file.extend_from_slice(text);
+    file.extend_from_slice(&symtab);
+    file.extend_from_slice(&strtab);
+
+    let mut section = |kind: u32, addr: usize, offset: usize, size: usize, link: u32| {
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&kind.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&(addr as u64).to_le_bytes());
+        file.extend_from_slice(&(offset as u64).to_le_bytes());
+        file.extend_from_slice(&(size as u64).to_le_bytes());
+        file.extend_from_slice(&link.to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+    };
+    section(0, 0, 0, 0, 0);
+    section(1, TEXT, text_off, text.len(), 0);
+    section(sh_type, 0, symtab_off, symtab.len(), 3);
+    section(3, 0, strtab_off, strtab.len(), 0);
+    file
+}
@@ -0,0 +1,67 @@
+//! Probe hits from several threads racing with registration.
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use spin::Mutex;
+
+use common::{asm, load_text, setup, trap_context, u32_bytes, OS, TEXT};
+use ruprobes::{uprobe_register, uprobes_trap_handler, ProbeType, TrapContext, UprobeHandler, UprobePostHandler, UprobeTrapResult};
+
+const HARTS: usize = 4;
+const ROUNDS: usize = 2_000;
+
+#[test]
+fn hits_race_with_registration() {
+    let _guard = setup("/test/smp");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP, asm::NOP]);
+    load_text(&code);
+    let hits = Arc::new(AtomicUsize::new(0));
+    let posts = Arc::new(AtomicUsize::new(0));
+    let pre_hits = hits.clone();
+    let handler: UprobeHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, _addr: usize| {
+        pre_hits.fetch_add(1, Ordering::SeqCst);
+    }));
+    let post_posts = posts.clone();
+    let post: UprobePostHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext| {
+        post_posts.fetch_add(1, Ordering::SeqCst);
+    }));
+    uprobe_register("/test/smp".into(), TEXT, handler, Some(post), ProbeType::Insn).unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let harts: Vec<_> = (0..HARTS)
+        .map(|hart| {
+            let done = done.clone();
+            thread::spawn(move || {
+                OS.set_thread_id(hart + 1);
+                let mut rounds = 0;
+                while !done.load(Ordering::SeqCst) || rounds < ROUNDS {
+                    let mut cx = trap_context(TEXT);
+                    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+                    let mut cx = trap_context(cx.sepc + 4);
+                    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+                    assert_eq!(cx.sepc, TEXT + 4);
+                    rounds += 1;
+                }
+                rounds
+            })
+        })
+        .collect();
+
+    // keep rewriting the table while the harts trap
+    for _ in 0..ROUNDS / 10 {
+        let other = uprobe_register("/test/smp".into(), TEXT + 8, Arc::new(Mutex::new(|_: &mut TrapContext, _: usize| {})), None, ProbeType::Insn).unwrap();
+        other.disable().unwrap();
+        other.enable().unwrap();
+        other.unregister().unwrap();
+    }
+    done.store(true, Ordering::SeqCst);
+
+    let rounds: usize = harts.into_iter().map(|hart| hart.join().unwrap()).sum();
+    assert_eq!(hits.load(Ordering::SeqCst), rounds);
+    assert_eq!(posts.load(Ordering::SeqCst), rounds);
+    assert_eq!(OS.read(TEXT + 8, 4), asm::NOP.to_le_bytes());
+}
@@ -0,0 +1,500 @@
+//! End-to-end probe flows on the RV64IMC interpreter in `common::emu`.
+
+mod common;
+
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use common::emu::{Emulator, Exit};
+use common::{asm, load_text, setup, Program, OS, TEXT};
+use ruprobes::mock::{PERM_R, PERM_W};
+use ruprobes::{uprobe_register, uretprobe_register, ProbeType, TrapContext, UprobeHandler, UprobePostHandler};
+use ruprobes::{uprobe_register_async, uprobe_register_async_drop, uprobe_register_latency, AsyncEvent, AsyncFuture, AsyncHandler, LatencyHistogram, PollAbi, UretprobeEntryHandler, UretprobeHandler, UretprobeInstance};
+
+const STACK_TOP: usize = 0x8_0000;
+const STEPS: usize = 10_000;
+
+static HITS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_hit(_cx: &mut TrapContext, _addr: usize) {
+    HITS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn handler() -> UprobeHandler {
+    Arc::new(Mutex::new(count_hit))
+}
+
+/// A post handler recording a0 every time it runs.
+fn record_a0() -> (UprobePostHandler, Arc<std::sync::Mutex<Vec<usize>>>) {
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let post: UprobePostHandler = Arc::new(Mutex::new(move |cx: &mut TrapContext| {
+        log.lock().unwrap().push(cx.x[10]);
+    }));
+    (post, seen)
+}
+
+fn boot(program: &Program) -> Emulator {
+    load_text(&program.code);
+    OS.map(STACK_TOP - 0x4000, 0x4000, PERM_R | PERM_W);
+    HITS.store(0, Ordering::SeqCst);
+    Emulator::new(TEXT, STACK_TOP)
+}
+
+#[test]
+fn insn_probe_in_a_loop() {
+    let _guard = setup("/emu/loop");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 0));
+    p.push(asm::li(asm::T0, 5));
+    let head = p.push(asm::addi(asm::A0, asm::A0, 1));
+    p.push(asm::addi(asm::T0, asm::T0, -1));
+    let back = p.here();
+    p.push(asm::bne(asm::T0, asm::ZERO, p.offset(back, head)));
+    p.push(asm::ECALL);
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/loop".into(), TEXT + head, handler(), Some(post), ProbeType::Insn).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[10], 5);
+    assert_eq!(HITS.load(Ordering::SeqCst), 5);
+    // the post handler runs after the out-of-line step, so it sees the incremented a0
+    assert_eq!(*seen.lock().unwrap(), [1, 2, 3, 4, 5]);
+    // one trap at the probe and one after the step in the slot per iteration
+    assert_eq!(emu.traps, 10);
+}
+
+#[test]
+fn return_probe_sees_return_value() {
+    let _guard = setup("/emu/ret");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 3));
+    let call = p.push_fixup();
+    p.push(asm::mv(asm::A1, asm::A0));
+    p.push(asm::ECALL);
+    let func = p.push(asm::addi(asm::SP, asm::SP, -16));
+    p.push(asm::sd(asm::RA, asm::SP, 8));
+    p.push(asm::addi(asm::A0, asm::A0, 1));
+    p.push(asm::ld(asm::RA, asm::SP, 8));
+    p.push(asm::addi(asm::SP, asm::SP, 16));
+    p.push(asm::RET);
+    p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/ret".into(), TEXT + func, handler(), Some(post), ProbeType::SyncFunc).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 4);
+    assert_eq!(emu.x[2], STACK_TOP);
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(*seen.lock().unwrap(), [4]);
+}
+
+#[test]
+fn two_return_probes_on_one_function() {
+    let _guard = setup("/emu/ret_twice");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 3));
+    let call = p.push_fixup();
+    p.push(asm::ECALL);
+    let func = p.push(asm::addi(asm::SP, asm::SP, -16));
+    p.push(asm::addi(asm::A0, asm::A0, 1));
+    p.push(asm::addi(asm::SP, asm::SP, 16));
+    p.push(asm::RET);
+    p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    let mut emu = boot(&p);
+    let (first, first_seen) = record_a0();
+    let (second, second_seen) = record_a0();
+
+    uprobe_register("/emu/ret_twice".into(), TEXT + func, handler(), Some(first), ProbeType::SyncFunc).unwrap();
+    uprobe_register("/emu/ret_twice".into(), TEXT + func, handler(), Some(second), ProbeType::SyncFunc).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(HITS.load(Ordering::SeqCst), 2);
+    assert_eq!(*first_seen.lock().unwrap(), [4]);
+    assert_eq!(*second_seen.lock().unwrap(), [4]);
+    // the entry, the end of its step and the trampoline, however many consumers there are
+    assert_eq!(emu.traps, 3);
+}
+
+#[test]
+fn return_probes_on_any_function_prologue() {
+    let _guard = setup("/emu/prologues");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 3));
+    let calls = [p.push_fixup(), p.push_fixup(), p.push_fixup()];
+    p.push(asm::mv(asm::A1, asm::A0));
+    p.push(asm::ECALL);
+    // a leaf function without a frame
+    let leaf = p.push(asm::add(asm::A0, asm::A0, asm::A0));
+    p.push(asm::RET);
+    // a frame too big for an addi immediate
+    let big = p.push(asm::lui(asm::T0, 1));
+    p.push(asm::sub(asm::SP, asm::SP, asm::T0));
+    p.push(asm::sd(asm::RA, asm::SP, 0));
+    p.push(asm::addi(asm::A0, asm::A0, 1));
+    p.push(asm::ld(asm::RA, asm::SP, 0));
+    p.push(asm::add(asm::SP, asm::SP, asm::T0));
+    p.push(asm::RET);
+    // returns right away, the emulated ret goes straight to the trampoline
+    let empty = p.push(asm::RET);
+    for (&call, func) in calls.iter().zip([leaf, big, empty]) {
+        p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    }
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    for func in [leaf, big, empty] {
+        uprobe_register("/emu/prologues".into(), TEXT + func, handler(), Some(post.clone()), ProbeType::SyncFunc).unwrap();
+    }
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 7);
+    assert_eq!(emu.x[2], STACK_TOP);
+    assert_eq!(HITS.load(Ordering::SeqCst), 3);
+    assert_eq!(*seen.lock().unwrap(), [6, 7, 7]);
+}
+
+/// Calls `fact(5)` and puts the result in a1, returns the offset of `fact`.
+fn factorial_program() -> (Program, usize) {
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 5));
+    let call = p.push_fixup();
+    p.push(asm::mv(asm::A1, asm::A0));
+    p.push(asm::ECALL);
+    // fact(n) = n <= 1 ? 1 : n * fact(n - 1)
+    let fact = p.push(asm::addi(asm::SP, asm::SP, -16));
+    p.push(asm::sd(asm::RA, asm::SP, 8));
+    p.push(asm::sd(asm::S0, asm::SP, 0));
+    p.push(asm::mv(asm::S0, asm::A0));
+    p.push(asm::li(asm::T0, 1));
+    let check = p.push_fixup();
+    p.push(asm::addi(asm::A0, asm::A0, -1));
+    let recurse = p.push_fixup();
+    p.push(asm::mul(asm::A0, asm::A0, asm::S0));
+    let skip = p.push_fixup();
+    let base = p.push(asm::li(asm::A0, 1));
+    let end = p.push(asm::ld(asm::RA, asm::SP, 8));
+    p.push(asm::ld(asm::S0, asm::SP, 0));
+    p.push(asm::addi(asm::SP, asm::SP, 16));
+    p.push(asm::RET);
+    p.fixup(call, fact, |offset| asm::jal(asm::RA, offset));
+    p.fixup(check, base, |offset| asm::bge(asm::T0, asm::A0, offset));
+    p.fixup(recurse, fact, |offset| asm::jal(asm::RA, offset));
+    p.fixup(skip, end, |offset| asm::jal(asm::ZERO, offset));
+    (p, fact)
+}
+
+#[test]
+fn return_probe_on_recursive_function() {
+    let _guard = setup("/emu/fact");
+    let (p, fact) = factorial_program();
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/fact".into(), TEXT + fact, handler(), Some(post), ProbeType::SyncFunc).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 120);
+    assert_eq!(emu.x[2], STACK_TOP);
+    assert_eq!(HITS.load(Ordering::SeqCst), 5);
+    // innermost call returns first
+    assert_eq!(*seen.lock().unwrap(), [1, 2, 6, 24, 120]);
+}
+
+#[test]
+fn uretprobe_pairs_entry_data_with_return_values() {
+    let _guard = setup("/emu/uretprobe");
+    let (p, fact) = factorial_program();
+    let mut emu = boot(&p);
+    let entries = Arc::new(AtomicUsize::new(0));
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let entry_count = entries.clone();
+    let entry: UretprobeEntryHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, ri: &mut UretprobeInstance| {
+        let seq = entry_count.fetch_add(1, Ordering::SeqCst) as u64;
+        ri.data_mut().copy_from_slice(&seq.to_le_bytes());
+        true
+    }));
+    let log = seen.clone();
+    let ret: UretprobeHandler = Arc::new(Mutex::new(move |cx: &mut TrapContext, ri: &UretprobeInstance| {
+        let seq = u64::from_le_bytes(ri.data().try_into().unwrap());
+        log.lock().unwrap().push((seq, ri.args()[0], cx.x[10]));
+    }));
+
+    let probe = uretprobe_register("/emu/uretprobe".into(), TEXT + fact, Some(entry), ret, 8).unwrap();
+    assert_eq!(probe.addr(), TEXT + fact);
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 120);
+    assert_eq!(emu.x[2], STACK_TOP);
+    // every return gets the data and arguments of its own entry
+    assert_eq!(*seen.lock().unwrap(), [(4, 1, 1), (3, 2, 2), (2, 3, 6), (1, 4, 24), (0, 5, 120)]);
+}
+
+#[test]
+fn latency_of_recursive_calls() {
+    let _guard = setup("/emu/latency");
+    let (p, fact) = factorial_program();
+    let mut emu = boot(&p);
+
+    let probe = uprobe_register_latency("/emu/latency".into(), TEXT + fact).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 120);
+    let latency = probe.latency().unwrap();
+    assert_eq!(latency.count, 5);
+    // the outermost call contains all the others
+    assert!(latency.min < latency.max);
+    assert!(latency.max < emu.steps as u64);
+    assert_eq!(latency.buckets.iter().sum::<u64>(), 5);
+    assert!(latency.buckets[LatencyHistogram::bucket_of(latency.min)] > 0);
+    assert!(latency.buckets[LatencyHistogram::bucket_of(latency.max)] > 0);
+    probe.unregister().unwrap();
+}
+
+#[test]
+fn async_probe_follows_futures_by_self() {
+    let _guard = setup("/emu/async");
+    const DATA: usize = 0x4_0000;
+    let mut p = Program::new();
+    p.push(asm::lui(asm::S0, (DATA >> 12) as u32));
+    p.push(asm::addi(asm::S1, asm::S0, 8));
+    // poll the future at s0 three times and the one at s1 once, interleaved
+    let mut calls = Vec::new();
+    for future in [asm::S0, asm::S1, asm::S0, asm::S0] {
+        p.push(asm::mv(asm::A0, future));
+        calls.push(p.push_fixup());
+    }
+    p.push(asm::ECALL);
+    // a future counting down to Ready: Poll::Ready is 0, Poll::Pending is 1
+    let poll = p.push(asm::ld(asm::T0, asm::A0, 0));
+    p.push(asm::addi(asm::T0, asm::T0, -1));
+    p.push(asm::sd(asm::T0, asm::A0, 0));
+    let check = p.push_fixup();
+    p.push(asm::li(asm::A0, 1));
+    p.push(asm::RET);
+    let ready = p.push(asm::li(asm::A0, 0));
+    p.push(asm::RET);
+    for call in calls {
+        p.fixup(call, poll, |offset| asm::jal(asm::RA, offset));
+    }
+    p.fixup(check, ready, |offset| asm::beq(asm::T0, asm::ZERO, offset));
+    let mut emu = boot(&p);
+    OS.map(DATA, 16, PERM_R | PERM_W);
+    OS.write(DATA, &[3u64.to_le_bytes(), 1u64.to_le_bytes()].concat());
+
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = events.clone();
+    let on_event: AsyncHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, event: AsyncEvent, future: &AsyncFuture| {
+        assert!(future.first_poll_time().is_some());
+        log.lock().unwrap().push((event, future.self_ptr() - DATA, future.polls()));
+    }));
+    uprobe_register_async("/emu/async".into(), TEXT + poll, on_event, PollAbi::default()).unwrap();
+    // a plain consumer gets its post handler only when a future is ready
+    let (post, seen) = record_a0();
+    uprobe_register("/emu/async".into(), TEXT + poll, handler(), Some(post), ProbeType::AsyncFunc).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(*events.lock().unwrap(), [
+        (AsyncEvent::FirstPoll, 0, 1),
+        (AsyncEvent::FirstPoll, 8, 1),
+        (AsyncEvent::Ready, 8, 1),
+        (AsyncEvent::Resume, 0, 2),
+        (AsyncEvent::Resume, 0, 3),
+        (AsyncEvent::Ready, 0, 3),
+    ]);
+    assert_eq!(HITS.load(Ordering::SeqCst), 4);
+    assert_eq!(*seen.lock().unwrap(), [0, 0]);
+}
+
+#[test]
+fn dropped_future_is_forgotten() {
+    let _guard = setup("/emu/async_drop");
+    const DATA: usize = 0x4_0000;
+    let mut p = Program::new();
+    p.push(asm::lui(asm::S0, (DATA >> 12) as u32));
+    // poll a future once, drop it while pending, then poll a new one at the same address
+    p.push(asm::mv(asm::A0, asm::S0));
+    let first = p.push_fixup();
+    p.push(asm::mv(asm::A0, asm::S0));
+    let dropped = p.push_fixup();
+    p.push(asm::li(asm::T0, 1));
+    p.push(asm::sd(asm::T0, asm::S0, 0));
+    p.push(asm::mv(asm::A0, asm::S0));
+    let second = p.push_fixup();
+    p.push(asm::ECALL);
+    // the same countdown future as above
+    let poll = p.push(asm::ld(asm::T0, asm::A0, 0));
+    p.push(asm::addi(asm::T0, asm::T0, -1));
+    p.push(asm::sd(asm::T0, asm::A0, 0));
+    let check = p.push_fixup();
+    p.push(asm::li(asm::A0, 1));
+    p.push(asm::RET);
+    let ready = p.push(asm::li(asm::A0, 0));
+    p.push(asm::RET);
+    let drop = p.push(asm::NOP);
+    p.push(asm::RET);
+    for call in [first, second] {
+        p.fixup(call, poll, |offset| asm::jal(asm::RA, offset));
+    }
+    p.fixup(dropped, drop, |offset| asm::jal(asm::RA, offset));
+    p.fixup(check, ready, |offset| asm::beq(asm::T0, asm::ZERO, offset));
+    let mut emu = boot(&p);
+    OS.map(DATA, 8, PERM_R | PERM_W);
+    OS.write(DATA, &3u64.to_le_bytes());
+
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = events.clone();
+    let on_event: AsyncHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, event: AsyncEvent, future: &AsyncFuture| {
+        log.lock().unwrap().push((event, future.polls()));
+    }));
+    let probe = uprobe_register_async("/emu/async_drop".into(), TEXT + poll, on_event, PollAbi::default()).unwrap();
+    uprobe_register_async_drop("/emu/async_drop".into(), TEXT + drop, &probe).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(*events.lock().unwrap(), [
+        (AsyncEvent::FirstPoll, 1),
+        (AsyncEvent::Dropped, 1),
+        (AsyncEvent::FirstPoll, 1),
+        (AsyncEvent::Ready, 1),
+    ]);
+}
+
+#[test]
+fn return_probe_with_interleaved_threads() {
+    let _guard = setup("/emu/threads");
+    let mut p = Program::new();
+    let call = p.push_fixup();
+    p.push(asm::mv(asm::A1, asm::A0));
+    p.push(asm::ECALL);
+    // sum(n) = n + (n - 1) + ... + 1, long enough for the threads to overlap
+    let func = p.push(asm::addi(asm::SP, asm::SP, -16));
+    p.push(asm::mv(asm::T0, asm::A0));
+    p.push(asm::li(asm::A0, 0));
+    let head = p.push(asm::add(asm::A0, asm::A0, asm::T0));
+    p.push(asm::addi(asm::T0, asm::T0, -1));
+    let back = p.here();
+    p.push(asm::bne(asm::T0, asm::ZERO, p.offset(back, head)));
+    p.push(asm::addi(asm::SP, asm::SP, 16));
+    p.push(asm::RET);
+    p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    let mut first = boot(&p);
+    let mut second = Emulator::new(TEXT, STACK_TOP - 0x1000);
+    first.x[10] = 10;
+    second.x[10] = 4;
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/threads".into(), TEXT + func, handler(), Some(post), ProbeType::SyncFunc).unwrap();
+
+    // a few instructions at a time, like two threads on one hart
+    let mut running = [(1, &mut first), (2, &mut second)];
+    let mut exited = [false; 2];
+    while !exited.iter().all(|&e| e) {
+        for (i, (tid, emu)) in running.iter_mut().enumerate() {
+            if exited[i] {
+                continue;
+            }
+            OS.set_thread_id(*tid);
+            match emu.run(3) {
+                Exit::StepLimit => {}
+                exit => {
+                    assert_eq!(exit, Exit::Ecall);
+                    exited[i] = true;
+                }
+            }
+        }
+    }
+    assert_eq!(first.x[11], 55);
+    assert_eq!(second.x[11], 10);
+    assert_eq!(HITS.load(Ordering::SeqCst), 2);
+    // the shorter call returns first
+    assert_eq!(*seen.lock().unwrap(), [10, 55]);
+}
+
+#[test]
+fn probes_on_compressed_code() {
+    let _guard = setup("/emu/compressed");
+    let mut p = Program::new();
+    p.push_c(asm::c_li(asm::A0, 0));
+    p.push_c(asm::c_li(asm::S0, 3));
+    let head = p.push_c(asm::c_addi(asm::A0, 1));
+    p.push_c(asm::c_addi(asm::S0, -1));
+    let back = p.here();
+    p.push_c(asm::c_bnez(asm::S0, p.offset(back, head)));
+    let call = p.push_fixup();
+    p.push_c(asm::c_mv(asm::A1, asm::A0));
+    p.push(asm::ECALL);
+    let func = p.push_c(asm::c_addi16sp(-64));
+    p.push_c(asm::c_sdsp(asm::RA, 8));
+    p.push_c(asm::c_addi(asm::A0, 10));
+    p.push_c(asm::c_ldsp(asm::RA, 8));
+    p.push_c(asm::c_addi16sp(64));
+    p.push_c(asm::C_RET);
+    p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    assert_eq!(asm::c_addi16sp(-64), asm::C_ADDI16SP_NEG64);
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/compressed".into(), TEXT + head, handler(), None, ProbeType::Insn).unwrap();
+    uprobe_register("/emu/compressed".into(), TEXT + func, handler(), Some(post), ProbeType::SyncFunc).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[11], 13);
+    assert_eq!(emu.x[2], STACK_TOP);
+    assert_eq!(HITS.load(Ordering::SeqCst), 4);
+    assert_eq!(*seen.lock().unwrap(), [13]);
+}
+
+#[test]
+fn probes_on_branches_and_calls() {
+    let _guard = setup("/emu/control_flow");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 0));
+    p.push(asm::li(asm::T0, 3));
+    let head = p.push(asm::addi(asm::T0, asm::T0, -1));
+    let call = p.push_fixup();
+    let back = p.here();
+    p.push(asm::bne(asm::T0, asm::ZERO, p.offset(back, head)));
+    let pc = p.push(asm::auipc(asm::A1, 0));
+    p.push(asm::ECALL);
+    let func = p.push_c(asm::c_addi(asm::A0, 2));
+    p.push_c(asm::c_jr(asm::RA));
+    p.fixup(call, func, |offset| asm::jal(asm::RA, offset));
+    let mut emu = boot(&p);
+    let (post, seen) = record_a0();
+
+    uprobe_register("/emu/control_flow".into(), TEXT + call, handler(), None, ProbeType::Insn).unwrap();
+    uprobe_register("/emu/control_flow".into(), TEXT + back, handler(), Some(post), ProbeType::Insn).unwrap();
+    uprobe_register("/emu/control_flow".into(), TEXT + pc, handler(), None, ProbeType::Insn).unwrap();
+    uprobe_register("/emu/control_flow".into(), TEXT + func + 2, handler(), None, ProbeType::Insn).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Ecall);
+    assert_eq!(emu.x[10], 6);
+    assert_eq!(emu.x[11], TEXT + pc);
+    assert_eq!(HITS.load(Ordering::SeqCst), 3 + 3 + 1 + 3);
+    assert_eq!(*seen.lock().unwrap(), [2, 4, 6]);
+    // emulated instructions never step out of line, so every hit is a single trap
+    assert_eq!(emu.traps, 10);
+}
+
+#[test]
+fn unprobed_breakpoint_stops_the_program() {
+    let _guard = setup("/emu/ebreak");
+    let mut p = Program::new();
+    p.push(asm::li(asm::A0, 1));
+    let brk = p.push(asm::EBREAK);
+    p.push(asm::ECALL);
+    let mut emu = boot(&p);
+
+    // some other probe so that the process is known to ruprobes
+    uprobe_register("/emu/ebreak".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+
+    assert_eq!(emu.run(STEPS), Exit::Breakpoint(TEXT + brk));
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+}
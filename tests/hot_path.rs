@@ -0,0 +1,29 @@
+//! Probe hits once a process and its threads are set up, which the trap path serves from
+//! preallocated state.
+
+mod common;
+
+use common::counting::{allocations, CountingAlloc};
+use common::{hits, setup};
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+#[test]
+fn probe_hits_do_not_allocate() {
+    let _guard = setup("/hot/alloc");
+    hits::register("/hot/alloc");
+    // the first hits map the XOL area and give the thread its slot and stacks
+    hits::step();
+    hits::emulate();
+    hits::call();
+
+    let count = allocations(|| {
+        for _ in 0..100 {
+            hits::step();
+            hits::emulate();
+            hits::call();
+        }
+    });
+    assert_eq!(count, 0);
+}
@@ -0,0 +1,435 @@
+//! Probes across processes: running ones, fork and exit.
+
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use common::{asm, load_text, C_EBREAK, setup, trap_context, u32_bytes, OS, TEXT};
+use ruprobes::{uprobe_register, uprobe_unregister, uprobes_exit, uprobes_fork, uprobes_init, uprobes_trap_handler, ForkPolicy, ProbeType, TrapContext, UprobeError, UprobeHandler, UprobePostHandler, UprobeTrapResult};
+
+const PARENT: usize = 1;
+const CHILD: usize = 2;
+const EBREAK: [u8; 4] = [0x02, 0x90, 0x02, 0x90];
+
+fn counter(count: &Arc<AtomicUsize>) -> UprobeHandler {
+    let count = count.clone();
+    Arc::new(Mutex::new(move |_cx: &mut TrapContext, _addr: usize| {
+        count.fetch_add(1, Ordering::SeqCst);
+    }))
+}
+
+fn post_counter(count: &Arc<AtomicUsize>) -> UprobePostHandler {
+    let count = count.clone();
+    Arc::new(Mutex::new(move |_cx: &mut TrapContext| {
+        count.fetch_add(1, Ordering::SeqCst);
+    }))
+}
+
+/// Like [`setup`], with [`PARENT`] running `path` instead of process 0.
+fn start(path: &str) -> std::sync::MutexGuard<'static, ()> {
+    let guard = setup(path);
+    OS.remove_process(0);
+    OS.set_pid(PARENT);
+    OS.set_exec_path(path);
+    uprobes_init();
+    OS.set_thread_id(1);
+    guard
+}
+
+/// Enter the function at [`TEXT`] as the current thread, returning the trampoline.
+fn enter(ra: usize) -> usize {
+    let mut cx = trap_context(TEXT);
+    cx.x[1] = ra;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    let trampoline = cx.x[1];
+    let mut cx = trap_context(cx.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+    trampoline
+}
+
+fn leave(trampoline: usize) -> usize {
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    cx.sepc
+}
+
+#[test]
+fn forked_child_inherits_probes_and_calls_in_flight() {
+    let _guard = start("/test/fork");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]));
+    let (hits, returns) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    uprobe_register("/test/fork".into(), TEXT, counter(&hits), Some(post_counter(&returns)), ProbeType::SyncFunc).unwrap();
+
+    let trampoline = enter(0x2_0000);
+    OS.fork(PARENT, CHILD);
+    uprobes_fork(PARENT, CHILD, 9).unwrap();
+
+    // the child returns from the call it was forked in, and can hit the probe again
+    OS.set_pid(CHILD);
+    OS.set_thread_id(9);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    assert_eq!(leave(trampoline), 0x2_0000);
+    let trampoline = enter(0x3_0000);
+    assert_eq!(leave(trampoline), 0x3_0000);
+
+    OS.set_pid(PARENT);
+    OS.set_thread_id(1);
+    assert_eq!(leave(trampoline), 0x2_0000);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    assert_eq!(returns.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn stripped_probe_is_removed_from_the_child() {
+    let _guard = start("/test/fork_strip");
+    let code = u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]);
+    load_text(&code);
+    let (hits, returns) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let probe = uprobe_register("/test/fork_strip".into(), TEXT, counter(&hits), Some(post_counter(&returns)), ProbeType::SyncFunc).unwrap();
+    probe.set_fork_policy(ForkPolicy::Strip).unwrap();
+
+    let trampoline = enter(0x2_0000);
+    OS.fork(PARENT, CHILD);
+    uprobes_fork(PARENT, CHILD, 1).unwrap();
+
+    OS.set_pid(CHILD);
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    // the call in flight still returns, without its handler
+    assert_eq!(leave(trampoline), 0x2_0000);
+    assert_eq!(returns.load(Ordering::SeqCst), 0);
+    // a breakpoint a debugger sets there is the child's own
+    OS.write(TEXT, &C_EBREAK);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    OS.write(TEXT, &code[..4]);
+
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    assert_eq!(leave(trampoline), 0x2_0000);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    assert_eq!(returns.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn exit_drops_the_calls_in_flight_of_the_process() {
+    let _guard = start("/test/exit");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]));
+    let (hits, returns) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    uprobe_register("/test/exit".into(), TEXT, counter(&hits), Some(post_counter(&returns)), ProbeType::SyncFunc).unwrap();
+
+    let trampoline = enter(0x2_0000);
+    OS.fork(PARENT, CHILD);
+    uprobes_fork(PARENT, CHILD, 9).unwrap();
+    OS.set_pid(CHILD);
+    OS.set_thread_id(9);
+    uprobes_exit();
+    OS.remove_process(CHILD);
+
+    // a later process with the same ids does not return through the dead one's call
+    OS.set_pid(CHILD);
+    OS.set_exec_path("/test/exit");
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    assert_eq!(cx.sepc, trampoline);
+    assert_eq!(returns.load(Ordering::SeqCst), 0);
+
+    OS.set_pid(PARENT);
+    OS.set_thread_id(1);
+    assert_eq!(leave(trampoline), 0x2_0000);
+    assert_eq!(returns.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn next_process_is_probed_afresh_after_the_last_exit() {
+    let _guard = start("/test/exit_last");
+    let code = u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]);
+    load_text(&code);
+    let hits = Arc::new(AtomicUsize::new(0));
+    uprobe_register("/test/exit_last".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    assert!(OS.xol_pages() > 0);
+    uprobes_exit();
+    OS.remove_process(PARENT);
+
+    // the new process maps the same path but has no breakpoint nor XOL area yet
+    OS.set_pid(CHILD);
+    OS.set_exec_path("/test/exit_last");
+    load_text(&code);
+    uprobe_register("/test/exit_last".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    assert!(OS.xol_pages() > 0);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+}
+
+/// Start process `pid` running `path` with `code` at [`TEXT`], leaving it the current one.
+fn spawn(pid: usize, path: &str, code: &[u8]) {
+    OS.set_pid(pid);
+    OS.set_exec_path(path);
+    load_text(code);
+    uprobes_init();
+}
+
+#[test]
+fn probes_are_armed_in_running_processes() {
+    let _guard = setup("/test/tracer");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    spawn(PARENT, "/test/daemon", &code);
+    spawn(CHILD, "/test/daemon", &code);
+    OS.set_pid(0);
+    let hits = Arc::new(AtomicUsize::new(0));
+    uprobe_register("/test/daemon".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    // the tracer itself does not run the path, so nothing is mapped before the first hit
+    assert_eq!(OS.xol_pages(), 0);
+
+    for pid in [PARENT, CHILD] {
+        OS.set_pid(pid);
+        assert_eq!(OS.read(TEXT, 4), EBREAK);
+        let mut cx = trap_context(TEXT);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert!(OS.is_mapped(cx.sepc));
+        assert_eq!(OS.read(cx.sepc + 4, 2), C_EBREAK);
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.sepc, TEXT + 4);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    OS.set_pid(0);
+    uprobe_unregister("/test/daemon".into(), TEXT).unwrap();
+    for pid in [PARENT, CHILD] {
+        OS.set_pid(pid);
+        assert_eq!(OS.read(TEXT, 8), code);
+    }
+}
+
+#[test]
+fn running_process_keeps_its_area_between_probes() {
+    let _guard = setup("/test/tracer_sessions");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    spawn(PARENT, "/test/daemon_sessions", &code);
+    let hits = Arc::new(AtomicUsize::new(0));
+
+    // tracing sessions come and go while the daemon runs
+    let mut pages = None;
+    for _ in 0..3 {
+        OS.set_pid(0);
+        uprobe_register("/test/daemon_sessions".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+        OS.set_pid(PARENT);
+        let mut cx = trap_context(TEXT);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        OS.set_pid(0);
+        uprobe_unregister("/test/daemon_sessions".into(), TEXT).unwrap();
+        assert_eq!(*pages.get_or_insert(OS.xol_pages()), OS.xol_pages());
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 8), code);
+}
+
+#[test]
+fn breakpoint_of_a_process_started_after_registration_is_not_probed() {
+    let _guard = setup("/test/tracer_deferred");
+    let hits = Arc::new(AtomicUsize::new(0));
+    // nothing runs the file yet, so the instructions are only read once a process does
+    for addr in [TEXT, TEXT + 4] {
+        uprobe_register("/test/debuggee".into(), addr, counter(&hits), None, ProbeType::Insn).unwrap();
+    }
+    let code = u32_bytes(&[asm::EBREAK, asm::addi(asm::A0, asm::A0, 1)]);
+    spawn(PARENT, "/test/debuggee", &code);
+
+    // the program's own breakpoint is left alone, the other probe is armed
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    assert_eq!(cx.sepc, TEXT);
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    OS.set_pid(0);
+    for addr in [TEXT, TEXT + 4] {
+        uprobe_unregister("/test/debuggee".into(), addr).unwrap();
+    }
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 8), code);
+}
+
+#[test]
+fn breakpoint_of_a_process_started_after_arming_is_not_probed() {
+    let _guard = setup("/test/tracer_late_debuggee");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    spawn(PARENT, "/test/late_debuggee", &code);
+    let hits = Arc::new(AtomicUsize::new(0));
+    OS.set_pid(0);
+    uprobe_register("/test/late_debuggee".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+
+    // a second process of the file starts with a breakpoint set by its debugger
+    let mut debugged = u32_bytes(&[asm::EBREAK]);
+    debugged.extend_from_slice(&code[4..]);
+    spawn(CHILD, "/test/late_debuggee", &debugged);
+    assert_eq!(OS.read(TEXT, 8), debugged);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+
+    OS.set_pid(0);
+    uprobe_unregister("/test/late_debuggee".into(), TEXT).unwrap();
+    OS.set_pid(CHILD);
+    assert_eq!(OS.read(TEXT, 8), debugged);
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 8), code);
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn running_process_is_patched_by_itself_without_remote_hooks() {
+    let _guard = setup("/test/tracer_hookless");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    let hits = Arc::new(AtomicUsize::new(0));
+    // the daemon probes its own executable, then the kernel can not reach into it anymore
+    spawn(PARENT, "/test/daemon_hookless", &code);
+    let own = uprobe_register("/test/daemon_hookless".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    OS.set_remote_hooks(false);
+
+    // a tracer can still register, disable and enable, the daemon only gets new probes at its next exec
+    OS.set_pid(0);
+    let probe = uprobe_register("/test/daemon_hookless".into(), TEXT + 4, counter(&hits), None, ProbeType::Insn).unwrap();
+    probe.disable().unwrap();
+    probe.enable().unwrap();
+    own.disable().unwrap();
+    own.enable().unwrap();
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT + 4, 4), code[4..]);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    let mut cx = trap_context(cx.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    // the breakpoint the tracer could not take out goes away at the daemon's next trap there
+    OS.set_pid(0);
+    own.disable().unwrap();
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT);
+    assert_eq!(OS.read(TEXT, 8), code);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    OS.set_pid(0);
+    uprobe_unregister("/test/daemon_hookless".into(), TEXT).unwrap();
+    uprobe_unregister("/test/daemon_hookless".into(), TEXT + 4).unwrap();
+}
+
+#[test]
+fn probe_is_armed_in_every_process_or_none() {
+    let _guard = setup("/test/tracer_fault");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1)]);
+    spawn(PARENT, "/test/daemon_fault", &code);
+    // the text of the second process is not mapped yet
+    OS.set_pid(CHILD);
+    OS.set_exec_path("/test/daemon_fault");
+    OS.set_pid(0);
+    let result = uprobe_register("/test/daemon_fault".into(), TEXT, counter(&Arc::new(AtomicUsize::new(0))), None, ProbeType::Insn);
+    assert_eq!(result.unwrap_err(), UprobeError::CopyFault);
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 4), code);
+}
+
+#[test]
+fn process_mapping_the_file_elsewhere_fails_the_registration() {
+    let _guard = setup("/test/tracer_pie");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    spawn(PARENT, "/test/pie", &code);
+    // loaded at another base, something else of the file is at the probed address
+    spawn(CHILD, "/test/pie", &u32_bytes(&[asm::NOP, asm::NOP]));
+
+    OS.set_pid(PARENT);
+    let result = uprobe_register("/test/pie".into(), TEXT, counter(&Arc::new(AtomicUsize::new(0))), None, ProbeType::Insn);
+    assert_eq!(result.unwrap_err(), UprobeError::InstructionMismatch);
+    assert_eq!(OS.read(TEXT, 8), code);
+    OS.set_pid(CHILD);
+    assert_eq!(OS.read(TEXT, 8), u32_bytes(&[asm::NOP, asm::NOP]));
+}
+
+#[test]
+fn probes_follow_the_file_whatever_path_runs_it() {
+    let _guard = setup("/test/tracer_link");
+    OS.add_link("/test/bin/link", "/test/bin/real");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    spawn(PARENT, "/test/bin/link", &code);
+    OS.set_pid(0);
+    let hits = Arc::new(AtomicUsize::new(0));
+    let probe = uprobe_register("/test/bin/real".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    assert_eq!(probe.path(), "/test/bin/real");
+
+    OS.set_pid(PARENT);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+    // started through the link as well
+    spawn(CHILD, "/test/bin/link", &code);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    uprobe_unregister("/test/bin/link".into(), TEXT).unwrap();
+    assert_eq!(OS.read(TEXT, 8), code);
+}
+
+#[test]
+fn stripped_probe_is_removed_by_the_child_without_remote_hooks() {
+    let _guard = start("/test/fork_strip_hookless");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    load_text(&code);
+    let hits = Arc::new(AtomicUsize::new(0));
+    let probe = uprobe_register("/test/fork_strip_hookless".into(), TEXT, counter(&hits), None, ProbeType::Insn).unwrap();
+    probe.set_fork_policy(ForkPolicy::Strip).unwrap();
+    OS.set_remote_hooks(false);
+
+    OS.fork(PARENT, CHILD);
+    uprobes_fork(PARENT, CHILD, 1).unwrap();
+
+    // the child still has the breakpoint, which goes away at its first trap there
+    OS.set_pid(CHILD);
+    assert_eq!(OS.read(TEXT, 4), EBREAK);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT);
+    assert_eq!(OS.read(TEXT, 8), code);
+    assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+    OS.set_pid(PARENT);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn processes_of_one_file_keep_their_calls_apart() {
+    let _guard = setup("/test/tracer_apart");
+    let code = u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]);
+    spawn(PARENT, "/test/apart", &code);
+    spawn(CHILD, "/test/apart", &code);
+    OS.set_thread_id(1);
+    let returns = Arc::new(AtomicUsize::new(0));
+    uprobe_register("/test/apart".into(), TEXT, counter(&Arc::new(AtomicUsize::new(0))), Some(post_counter(&returns)), ProbeType::SyncFunc).unwrap();
+
+    // the same thread id in both, each with a trampoline in its own XOL area
+    OS.set_pid(PARENT);
+    let parent_trampoline = enter(0x2_0000);
+    OS.set_pid(CHILD);
+    let child_trampoline = enter(0x3_0000);
+    assert_ne!(parent_trampoline, child_trampoline);
+    OS.set_pid(PARENT);
+    assert_eq!(leave(parent_trampoline), 0x2_0000);
+    OS.set_pid(CHILD);
+    assert_eq!(leave(child_trampoline), 0x3_0000);
+    assert_eq!(returns.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,123 @@
+//! Registering probes by function name, resolved from a generated ELF file.
+
+mod common;
+
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use common::{asm, elf_file, load_text, setup, trap_context, u32_bytes, Sym, OS, TEXT};
+use ruprobes::{resolve_symbol, uprobe_register_symbol, uprobes_trap_handler, ProbeType, TrapContext, UprobeError, UprobeHandler, UprobeTrapResult};
+
+fn nop_handler(_cx: &mut TrapContext, _addr: usize) {}
+
+fn handler() -> UprobeHandler {
+    Arc::new(Mutex::new(nop_handler))
+}
+
+fn symbols() -> Vec<Sym> {
+    vec![
+        Sym { name: "main", value: TEXT, size: 8 },
+        Sym { name: "handle_request", value: TEXT + 8, size: 12 },
+        Sym { name: "unsized", value: TEXT + 12, size: 0 },
+    ]
+}
+
+/// `main`, then `handle_request` starting with two compressed instructions.
+fn code() -> Vec<u8> {
+    let mut code = u32_bytes(&[asm::NOP, asm::NOP]);
+    code.extend_from_slice(&asm::C_ADDI16SP_NEG64.to_le_bytes());
+    code.extend_from_slice(&asm::C_ADDI16SP_NEG64.to_le_bytes());
+    code.extend_from_slice(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::RET]));
+    code
+}
+
+#[test]
+fn names_and_offsets_are_resolved() {
+    let _guard = setup("/bin/resolve");
+    OS.add_file("/bin/resolve", &elf_file(&symbols(), &code(), 2));
+
+    assert_eq!(resolve_symbol("/bin/resolve", "main"), Ok(TEXT));
+    assert_eq!(resolve_symbol("/bin/resolve", "handle_request"), Ok(TEXT + 8));
+    assert_eq!(resolve_symbol("/bin/resolve", "handle_request+0x4"), Ok(TEXT + 12));
+    assert_eq!(resolve_symbol("/bin/resolve", "handle_request+8"), Ok(TEXT + 16));
+    // without a size the function ends with its section
+    assert_eq!(resolve_symbol("/bin/resolve", "unsized+4"), Ok(TEXT + 16));
+}
+
+#[test]
+fn dynsym_is_searched() {
+    let _guard = setup("/bin/dynsym");
+    OS.add_file("/bin/dynsym", &elf_file(&symbols(), &code(), 11));
+
+    assert_eq!(resolve_symbol("/bin/dynsym", "handle_request+2"), Ok(TEXT + 10));
+}
+
+#[test]
+fn bad_specs_are_rejected() {
+    let _guard = setup("/bin/bad");
+    OS.add_file("/bin/bad", &elf_file(&symbols(), &code(), 2));
+
+    assert_eq!(resolve_symbol("/bin/bad", "missing"), Err(UprobeError::SymbolNotFound));
+    assert_eq!(resolve_symbol("/bin/bad", "handle"), Err(UprobeError::SymbolNotFound));
+    assert_eq!(resolve_symbol("/bin/bad", "+4"), Err(UprobeError::SymbolNotFound));
+    assert_eq!(resolve_symbol("/bin/bad", "main+"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "main+0xzz"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "main+3"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "main+8"), Err(UprobeError::BadSymbolOffset));
+    // inside the 4 byte instructions of handle_request
+    assert_eq!(resolve_symbol("/bin/bad", "handle_request+6"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "handle_request+10"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "unsized+8"), Err(UprobeError::BadSymbolOffset));
+    assert_eq!(resolve_symbol("/bin/bad", "main+0xffffffffffffffff"), Err(UprobeError::BadSymbolOffset));
+}
+
+#[test]
+fn files_that_are_not_elf_are_rejected() {
+    let _guard = setup("/bin/not_elf");
+    OS.add_file("/bin/not_elf", &[0; 128]);
+    let mut truncated = elf_file(&symbols(), &code(), 2);
+    truncated.truncate(100);
+    OS.add_file("/bin/truncated", &truncated);
+
+    assert_eq!(resolve_symbol("/bin/not_elf", "main"), Err(UprobeError::NotElf));
+    assert_eq!(resolve_symbol("/bin/truncated", "main"), Err(UprobeError::FileRead));
+    assert_eq!(resolve_symbol("/bin/missing", "main"), Err(UprobeError::FileRead));
+}
+
+#[test]
+fn sizes_from_the_file_are_not_trusted() {
+    let _guard = setup("/bin/crafted");
+    let mut file = elf_file(&symbols(), &code(), 2);
+    // the symbol table claims a terabyte, which is read a chunk at a time until the file ends
+    let mut shoff = [0; 8];
+    shoff.copy_from_slice(&file[40..48]);
+    let size = u64::from_le_bytes(shoff) as usize + 2 * 64 + 32;
+    file[size..size + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+    OS.add_file("/bin/crafted", &file);
+
+    assert_eq!(resolve_symbol("/bin/crafted", "main"), Err(UprobeError::FileRead));
+}
+
+#[test]
+fn register_by_symbol() {
+    let _guard = setup("/bin/server");
+    load_text(&code());
+    OS.add_file("/bin/server", &elf_file(&symbols(), &code(), 2));
+
+    let probe = uprobe_register_symbol("/bin/server".into(), "handle_request+0x4", handler(), None, ProbeType::Insn).unwrap();
+    let addr = probe.addr();
+    assert_eq!(addr, TEXT + 12);
+    assert_eq!(OS.read(TEXT + 12, 4), [0x02, 0x90, 0x02, 0x90]);
+
+    let mut cx = trap_context(addr);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_ne!(cx.sepc, addr);
+
+    probe.unregister().unwrap();
+    assert_eq!(OS.read(TEXT + 12, 4), asm::addi(asm::A0, asm::A0, 1).to_le_bytes());
+    assert_eq!(
+        uprobe_register_symbol("/bin/server".into(), "nope", handler(), None, ProbeType::Insn),
+        Err(UprobeError::SymbolNotFound)
+    );
+}
@@ -0,0 +1,891 @@
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use spin::Mutex;
+
+use common::{asm, load_text, setup, trap_context, u16_bytes, u32_bytes, C_EBREAK, OS, TEXT};
+use ruprobes::{uprobe_register, uprobe_register_with_data, uprobe_unregister, uprobes_init, uprobes_thread_exit, uprobes_trap_handler, uretprobe_register, ProbeType, TrapContext, UprobeError, UprobeHandler, UprobePostHandler, UprobeTrapResult};
+use ruprobes::mock::PERM_X;
+use ruprobes::{uprobe_register_latency, LatencyHistogram, UretprobeEntryHandler, UretprobeHandler, UretprobeInstance, LATENCY_BUCKETS, UPROBE_MAX_DATA_SIZE};
+
+static HITS: AtomicUsize = AtomicUsize::new(0);
+static LAST_ADDR: AtomicUsize = AtomicUsize::new(0);
+
+fn count_hit(_cx: &mut TrapContext, addr: usize) {
+    HITS.fetch_add(1, Ordering::SeqCst);
+    LAST_ADDR.store(addr, Ordering::SeqCst);
+}
+
+fn reset_hits() {
+    HITS.store(0, Ordering::SeqCst);
+    LAST_ADDR.store(0, Ordering::SeqCst);
+}
+
+fn handler() -> UprobeHandler {
+    Arc::new(Mutex::new(count_hit))
+}
+
+fn post_handler(counter: &Arc<AtomicUsize>) -> UprobePostHandler {
+    let counter = counter.clone();
+    Arc::new(Mutex::new(move |_cx: &mut TrapContext| {
+        counter.fetch_add(1, Ordering::SeqCst);
+    }))
+}
+
+#[test]
+fn insn_probe_single_steps_out_of_line() {
+    let _guard = setup("/test/insn");
+    reset_hits();
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    load_text(&code);
+    let posts = Arc::new(AtomicUsize::new(0));
+
+    uprobe_register("/test/insn".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::Insn).unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    assert_eq!(OS.read(TEXT + 4, 4), code[4..]);
+
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(LAST_ADDR.load(Ordering::SeqCst), TEXT);
+    assert_eq!(posts.load(Ordering::SeqCst), 0);
+    // execution continues in the slot: the original instruction followed by c.ebreak
+    let slot = cx.sepc;
+    assert_ne!(slot, TEXT);
+    assert_eq!(OS.perm(slot), Some(PERM_X));
+    assert_eq!(OS.read(slot, 4), code[..4]);
+    assert_eq!(OS.read(slot + 4, 2), C_EBREAK);
+
+    let mut cx = trap_context(slot + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(posts.load(Ordering::SeqCst), 1);
+    assert_eq!(cx.sepc, TEXT + 4);
+}
+
+#[test]
+fn threads_single_step_in_their_own_slots() {
+    let _guard = setup("/test/insn_threads");
+    reset_hits();
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::addi(asm::A1, asm::A1, 1), asm::NOP]));
+    let posts = Arc::new(AtomicUsize::new(0));
+    for addr in [TEXT, TEXT + 4] {
+        uprobe_register("/test/insn_threads".into(), addr, handler(), Some(post_handler(&posts)), ProbeType::Insn).unwrap();
+    }
+
+    // thread 1 is preempted in its slot while thread 2 hits the same probe, then another one
+    OS.set_thread_id(1);
+    let mut first = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut first), UprobeTrapResult::Handled);
+    OS.set_thread_id(2);
+    let mut second = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut second), UprobeTrapResult::Handled);
+    assert_ne!(first.sepc, second.sepc);
+    assert_eq!(OS.read(first.sepc, 4), asm::addi(asm::A0, asm::A0, 1).to_le_bytes());
+    assert_eq!(OS.read(second.sepc, 4), asm::addi(asm::A0, asm::A0, 1).to_le_bytes());
+
+    let mut cx = trap_context(second.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    // the slot is reused for the next step of the same thread
+    assert_eq!(cx.sepc, second.sepc);
+    assert_eq!(OS.read(second.sepc, 4), asm::addi(asm::A1, asm::A1, 1).to_le_bytes());
+
+    // thread 1 finally runs its ebreak, its slot still holds its instruction
+    OS.set_thread_id(1);
+    assert_eq!(OS.read(first.sepc, 4), asm::addi(asm::A0, asm::A0, 1).to_le_bytes());
+    let mut cx = trap_context(first.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+
+    OS.set_thread_id(2);
+    let mut cx = trap_context(second.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 8);
+    assert_eq!(HITS.load(Ordering::SeqCst), 3);
+    assert_eq!(posts.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn slots_of_exited_threads_are_reused() {
+    let _guard = setup("/test/insn_thread_churn");
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]));
+    uprobe_register("/test/insn_thread_churn".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+
+    // more threads than the XOL area has slots, one after the other
+    let mut slot = 0;
+    for tid in 1..5000 {
+        OS.set_thread_id(tid);
+        let mut cx = trap_context(TEXT);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert!(slot == 0 || cx.sepc == slot);
+        slot = cx.sepc;
+        let mut cx = trap_context(slot + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        uprobes_thread_exit(tid);
+    }
+}
+
+#[test]
+fn step_in_flight_survives_unregister() {
+    let _guard = setup("/test/insn_unregister");
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]));
+    let posts = Arc::new(AtomicUsize::new(0));
+    let probe = uprobe_register("/test/insn_unregister".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::Insn).unwrap();
+
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    let slot = cx.sepc;
+    probe.unregister().unwrap();
+    // the thread slot stays mapped until the step is done
+    assert!(OS.is_mapped(slot));
+
+    let mut cx = trap_context(slot + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+    assert_eq!(posts.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn sync_func_probe_steps_entry_and_hijacks_ra() {
+    let _guard = setup("/test/sync_func");
+    reset_hits();
+    let code = u32_bytes(&[asm::addi(asm::SP, asm::SP, -32), asm::NOP, asm::RET]);
+    load_text(&code);
+    let posts = Arc::new(AtomicUsize::new(0));
+
+    uprobe_register("/test/sync_func".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::SyncFunc).unwrap();
+
+    let mut cx = trap_context(TEXT);
+    cx.x[1] = 0x2_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    let trampoline = cx.x[1];
+    assert_ne!(trampoline, 0x2_0000);
+    assert_eq!(OS.read(trampoline, 2), C_EBREAK);
+    // the first instruction runs out of line like for an Insn probe
+    let slot = cx.sepc;
+    assert_eq!(OS.read(slot, 4), code[..4]);
+    let mut cx = trap_context(slot + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 4);
+    assert_eq!(posts.load(Ordering::SeqCst), 0);
+
+    // the function returns into the trampoline
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(posts.load(Ordering::SeqCst), 1);
+    assert_eq!(cx.sepc, 0x2_0000);
+}
+
+#[test]
+fn return_addresses_are_kept_per_thread() {
+    let _guard = setup("/test/threads");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -32), asm::NOP, asm::RET]));
+    let posts = Arc::new(AtomicUsize::new(0));
+    uprobe_register("/test/threads".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::SyncFunc).unwrap();
+
+    // thread 1 enters, thread 2 enters, then thread 1 returns first
+    let mut trampoline = 0;
+    for (tid, ra) in [(1, 0x2_0000), (2, 0x3_0000)] {
+        OS.set_thread_id(tid);
+        let mut cx = trap_context(TEXT);
+        cx.x[1] = ra;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        trampoline = cx.x[1];
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    }
+    // a thread that never entered the function
+    OS.set_thread_id(3);
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Error(UprobeError::NoPendingReturn));
+    for (tid, ra) in [(1, 0x2_0000), (2, 0x3_0000)] {
+        OS.set_thread_id(tid);
+        let mut cx = trap_context(trampoline);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.sepc, ra);
+    }
+    assert_eq!(posts.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn thread_exiting_in_a_call_leaves_nothing_to_its_successor() {
+    let _guard = setup("/test/thread_exit");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -32), asm::NOP, asm::RET]));
+    let posts = Arc::new(AtomicUsize::new(0));
+    uprobe_register("/test/thread_exit".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::SyncFunc).unwrap();
+
+    // thread 1 dies inside the function, and its id goes to a new thread
+    OS.set_thread_id(1);
+    let mut trampoline = 0;
+    for ra in [0x2_0000, 0x3_0000] {
+        let mut cx = trap_context(TEXT);
+        cx.x[1] = ra;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        trampoline = cx.x[1];
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        if ra == 0x2_0000 {
+            uprobes_thread_exit(1);
+        }
+    }
+
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, 0x3_0000);
+    // the call of the dead thread is gone
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Error(UprobeError::NoPendingReturn));
+    assert_eq!(posts.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn sync_func_probe_without_post_handler_keeps_ra() {
+    let _guard = setup("/test/sync_func_no_post");
+    reset_hits();
+    load_text(&u16_bytes(&[asm::C_ADDI16SP_NEG64, asm::C_RET]));
+
+    uprobe_register("/test/sync_func_no_post".into(), TEXT, handler(), None, ProbeType::SyncFunc).unwrap();
+    assert_eq!(OS.read(TEXT, 2), C_EBREAK);
+    assert_eq!(OS.read(TEXT + 2, 2), asm::C_RET.to_le_bytes());
+
+    let mut cx = trap_context(TEXT);
+    cx.x[1] = 0x2_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_eq!(cx.x[1], 0x2_0000);
+    assert_eq!(OS.read(cx.sepc, 4), [asm::C_ADDI16SP_NEG64.to_le_bytes(), C_EBREAK].concat());
+
+    let mut cx = trap_context(cx.sepc + 2);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 2);
+}
+
+#[test]
+fn illegal_instruction_is_rejected_and_left_untouched() {
+    let _guard = setup("/test/illegal");
+    let code = u32_bytes(&[asm::ECALL]);
+    load_text(&code);
+
+    let result = uprobe_register("/test/illegal".into(), TEXT, handler(), None, ProbeType::Insn);
+    assert_eq!(result.unwrap_err(), UprobeError::IllegalInstruction);
+    assert_eq!(OS.read(TEXT, 4), code);
+    assert_eq!(OS.xol_pages(), 0);
+    // nothing was kept, so the same address can be registered again
+    assert_eq!(uprobe_unregister("/test/illegal".into(), TEXT), Err(UprobeError::UnknownPath));
+}
+
+#[test]
+fn closure_handlers_keep_their_own_state() {
+    let _guard = setup("/test/closures");
+    load_text(&u32_bytes(&[asm::NOP, asm::NOP]));
+    let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+    for (id, addr) in [(7, TEXT), (9, TEXT + 4)] {
+        let seen = seen.clone();
+        let handler: UprobeHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, addr: usize| {
+            seen.lock().unwrap().push((id, addr));
+        }));
+        uprobe_register("/test/closures".into(), addr, handler, None, ProbeType::Insn).unwrap();
+    }
+
+    assert_eq!(uprobes_trap_handler(&mut trap_context(TEXT + 4)), UprobeTrapResult::Handled);
+    assert_eq!(uprobes_trap_handler(&mut trap_context(TEXT)), UprobeTrapResult::Handled);
+    assert_eq!(*seen.lock().unwrap(), [(9, TEXT + 4), (7, TEXT)]);
+}
+
+struct Counters {
+    program: usize,
+    entries: usize,
+    posts: usize,
+}
+
+fn count_entry(cx: &mut TrapContext, _addr: usize, data: &mut Counters) {
+    data.entries += 1;
+    cx.x[10] = data.program;
+}
+
+fn count_post(cx: &mut TrapContext, data: &mut Counters) {
+    data.posts += 1;
+    cx.x[11] = data.entries * 100 + data.posts;
+}
+
+#[test]
+fn handlers_share_per_probe_data() {
+    let _guard = setup("/test/with_data");
+    load_text(&u32_bytes(&[asm::NOP, asm::NOP]));
+    let data = Counters { program: 42, entries: 0, posts: 0 };
+
+    uprobe_register_with_data("/test/with_data".into(), TEXT, data, count_entry, Some(count_post), ProbeType::Insn).unwrap();
+
+    for round in 1..=2 {
+        let mut cx = trap_context(TEXT);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.x[10], 42);
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.x[11], round * 101);
+        assert_eq!(cx.sepc, TEXT + 4);
+    }
+}
+
+#[test]
+fn pc_relative_instructions_are_emulated() {
+    let _guard = setup("/test/emulate");
+    reset_hits();
+    let code = u32_bytes(&[asm::jal(asm::RA, 0x100), asm::auipc(asm::A0, 0x12345), asm::bne(asm::A0, asm::ZERO, -8)]);
+    load_text(&code);
+    let posts = Arc::new(AtomicUsize::new(0));
+    for addr in [TEXT, TEXT + 4, TEXT + 8] {
+        uprobe_register("/test/emulate".into(), addr, handler(), Some(post_handler(&posts)), ProbeType::Insn).unwrap();
+    }
+
+    // the post handler runs at the probe hit, there is no step in the slot
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 0x100);
+    assert_eq!(cx.x[1], TEXT + 4);
+    assert_eq!(posts.load(Ordering::SeqCst), 1);
+
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 8);
+    assert_eq!(cx.x[10], TEXT + 4 + 0x1234_5000);
+
+    let mut cx = trap_context(TEXT + 8);
+    cx.x[10] = 1;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT);
+    let mut cx = trap_context(TEXT + 8);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 12);
+
+    assert_eq!(HITS.load(Ordering::SeqCst), 4);
+    assert_eq!(posts.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn compressed_jumps_are_emulated() {
+    let _guard = setup("/test/emulate_c");
+    load_text(&u16_bytes(&[asm::c_jalr(asm::T0), asm::c_jr(asm::RA), asm::c_j(-4)]));
+    for addr in [TEXT, TEXT + 2, TEXT + 4] {
+        uprobe_register("/test/emulate_c".into(), addr, handler(), None, ProbeType::Insn).unwrap();
+    }
+
+    let mut cx = trap_context(TEXT);
+    cx.x[5] = 0x3_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, 0x3_0000);
+    assert_eq!(cx.x[1], TEXT + 2);
+
+    let mut cx = trap_context(TEXT + 2);
+    cx.x[1] = 0x4_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, 0x4_0000);
+    assert_eq!(cx.x[1], 0x4_0000);
+
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT);
+}
+
+#[test]
+fn sync_func_probe_on_a_function_that_returns_at_once() {
+    let _guard = setup("/test/sync_func_ret");
+    load_text(&u32_bytes(&[asm::RET]));
+    let posts = Arc::new(AtomicUsize::new(0));
+
+    uprobe_register("/test/sync_func_ret".into(), TEXT, handler(), Some(post_handler(&posts)), ProbeType::SyncFunc).unwrap();
+
+    // the emulated ret already returns into the trampoline
+    let mut cx = trap_context(TEXT);
+    cx.x[1] = 0x2_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, cx.x[1]);
+    assert_eq!(posts.load(Ordering::SeqCst), 0);
+
+    let mut cx = trap_context(cx.sepc);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, 0x2_0000);
+    assert_eq!(posts.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn uretprobe_entry_handler_can_skip_the_return() {
+    let _guard = setup("/test/uretprobe_skip");
+    reset_hits();
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]));
+    let returns = Arc::new(AtomicUsize::new(0));
+    let count = returns.clone();
+    // only calls with a0 != 0 are followed to their return
+    let entry: UretprobeEntryHandler = Arc::new(Mutex::new(|cx: &mut TrapContext, ri: &mut UretprobeInstance| {
+        assert_eq!(ri.func(), TEXT);
+        assert_eq!(ri.ret_addr(), 0x2_0000);
+        cx.x[10] != 0
+    }));
+    let ret: UretprobeHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, ri: &UretprobeInstance| {
+        assert_eq!(ri.args()[0], 7);
+        assert!(ri.data().is_empty());
+        count.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    uretprobe_register("/test/uretprobe_skip".into(), TEXT, Some(entry), ret, 0).unwrap();
+    // a plain consumer on the same function still gets its pre-handler
+    uprobe_register("/test/uretprobe_skip".into(), TEXT, handler(), None, ProbeType::SyncFunc).unwrap();
+
+    for a0 in [0, 7] {
+        let mut cx = trap_context(TEXT);
+        cx.x[1] = 0x2_0000;
+        cx.x[10] = a0;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        let trampoline = cx.x[1];
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        if a0 == 0 {
+            assert_eq!(trampoline, 0x2_0000);
+            continue;
+        }
+        assert_ne!(trampoline, 0x2_0000);
+        let mut cx = trap_context(trampoline);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.sepc, 0x2_0000);
+    }
+    assert_eq!(HITS.load(Ordering::SeqCst), 2);
+    assert_eq!(returns.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn latency_probe_fills_a_log2_histogram() {
+    let _guard = setup("/test/latency");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]));
+
+    let probe = uprobe_register_latency("/test/latency".into(), TEXT).unwrap();
+    assert_eq!(probe.latency(), Some(LatencyHistogram::new()));
+
+    for duration in [100, 3, 0] {
+        let mut cx = trap_context(TEXT);
+        cx.x[1] = 0x2_0000;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        let trampoline = cx.x[1];
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        OS.advance_time(duration);
+        let mut cx = trap_context(trampoline);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.sepc, 0x2_0000);
+    }
+
+    let latency = probe.latency().unwrap();
+    assert_eq!((latency.count, latency.min, latency.max, latency.sum), (3, 0, 100, 103));
+    assert_eq!(latency.mean(), Some(34));
+    let mut buckets = [0; LATENCY_BUCKETS];
+    buckets[0] = 1;
+    buckets[1] = 1;
+    buckets[6] = 1;
+    assert_eq!(latency.buckets, buckets);
+
+    // plain probes have no histogram
+    let other = uprobe_register("/test/latency".into(), TEXT, handler(), None, ProbeType::SyncFunc).unwrap();
+    assert_eq!(other.latency(), None);
+    probe.clone().unregister().unwrap();
+    assert_eq!(probe.latency(), None);
+    other.unregister().unwrap();
+}
+
+#[test]
+fn uretprobe_data_size_is_limited() {
+    let _guard = setup("/test/uretprobe_size");
+    load_text(&u32_bytes(&[asm::RET]));
+    let ret: UretprobeHandler = Arc::new(Mutex::new(|_cx: &mut TrapContext, _ri: &UretprobeInstance| {}));
+
+    let result = uretprobe_register("/test/uretprobe_size".into(), TEXT, None, ret.clone(), UPROBE_MAX_DATA_SIZE + 1);
+    assert_eq!(result.unwrap_err(), UprobeError::DataTooLarge);
+    assert_eq!(OS.read(TEXT, 4), asm::RET.to_le_bytes());
+    uretprobe_register("/test/uretprobe_size".into(), TEXT, None, ret, UPROBE_MAX_DATA_SIZE).unwrap();
+}
+
+/// A consumer pushing `(name, stage)` to `log` from both handlers.
+fn logging_consumer(log: &Arc<std::sync::Mutex<Vec<(&'static str, &'static str)>>>, name: &'static str) -> (UprobeHandler, Option<UprobePostHandler>) {
+    let pre_log = log.clone();
+    let post_log = log.clone();
+    let handler: UprobeHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext, _addr: usize| {
+        pre_log.lock().unwrap().push((name, "pre"));
+    }));
+    let post_handler: UprobePostHandler = Arc::new(Mutex::new(move |_cx: &mut TrapContext| {
+        post_log.lock().unwrap().push((name, "post"));
+    }));
+    (handler, Some(post_handler))
+}
+
+/// Hit the `Insn` probe at [`TEXT`] and finish the out-of-line step.
+fn hit_insn_probe() {
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    if cx.sepc != TEXT {
+        let mut cx = trap_context(cx.sepc + 4);
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        assert_eq!(cx.sepc, TEXT + 4);
+    }
+}
+
+#[test]
+fn consumers_at_the_same_address_all_run() {
+    let _guard = setup("/test/consumers");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    load_text(&code);
+    let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let (handler, post) = logging_consumer(&log, "first");
+    let first = uprobe_register("/test/consumers".into(), TEXT, handler, post, ProbeType::Insn).unwrap();
+    let pages = OS.xol_pages();
+    let (handler, post) = logging_consumer(&log, "second");
+    let second = uprobe_register("/test/consumers".into(), TEXT, handler, post, ProbeType::Insn).unwrap();
+    assert_ne!(first, second);
+    // the second consumer shares the breakpoint and the slot
+    assert_eq!(OS.xol_pages(), pages);
+
+    hit_insn_probe();
+    assert_eq!(*log.lock().unwrap(), [("first", "pre"), ("second", "pre"), ("first", "post"), ("second", "post")]);
+
+    log.lock().unwrap().clear();
+    first.unregister().unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    hit_insn_probe();
+    assert_eq!(*log.lock().unwrap(), [("second", "pre"), ("second", "post")]);
+
+    second.unregister().unwrap();
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    assert_eq!(OS.xol_pages(), 0);
+}
+
+#[test]
+fn unregistered_consumer_is_not_called_on_return() {
+    let _guard = setup("/test/consumers_return");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]));
+    let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut probes = Vec::new();
+    for name in ["first", "second", "third"] {
+        let (handler, post) = logging_consumer(&log, name);
+        probes.push(uprobe_register("/test/consumers_return".into(), TEXT, handler, post, ProbeType::SyncFunc).unwrap());
+    }
+
+    let mut cx = trap_context(TEXT);
+    cx.x[1] = 0x2_0000;
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    let trampoline = cx.x[1];
+    let mut cx = trap_context(cx.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    log.lock().unwrap().clear();
+
+    // the call in flight keeps the handlers of the others only
+    probes.remove(1).unregister().unwrap();
+    let mut cx = trap_context(trampoline);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, 0x2_0000);
+    assert_eq!(*log.lock().unwrap(), [("first", "post"), ("third", "post")]);
+}
+
+#[test]
+fn probes_share_one_xol_area() {
+    let _guard = setup("/test/xol_shared");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1); 256]);
+    load_text(&code);
+
+    let mut probes = Vec::new();
+    for i in 0..256 {
+        probes.push(uprobe_register("/test/xol_shared".into(), TEXT + i * 4, handler(), None, ProbeType::Insn).unwrap());
+    }
+    let pages = OS.xol_pages();
+    assert!(pages > 0 && pages <= 4);
+    // the thread slot comes from the same area
+    let mut cx = trap_context(TEXT + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(OS.xol_pages(), pages);
+    let mut cx = trap_context(cx.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT + 8);
+
+    for probe in probes {
+        probe.unregister().unwrap();
+    }
+    assert_eq!(OS.xol_pages(), 0);
+    assert_eq!(OS.read(TEXT, code.len()), code);
+}
+
+#[test]
+fn xol_slots_are_reused_after_unregister() {
+    let _guard = setup("/test/xol_reuse");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::addi(asm::A0, asm::A0, 1)]);
+    load_text(&code);
+
+    // keeps the area mapped while the other probe comes and goes
+    let keeper = uprobe_register("/test/xol_reuse".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    let pages = OS.xol_pages();
+    for _ in 0..5000 {
+        let probe = uprobe_register("/test/xol_reuse".into(), TEXT + 4, handler(), None, ProbeType::Insn).unwrap();
+        probe.unregister().unwrap();
+    }
+    assert_eq!(OS.xol_pages(), pages);
+    assert_eq!(OS.read(TEXT + 4, 4), code[4..]);
+    keeper.unregister().unwrap();
+    assert_eq!(OS.xol_pages(), 0);
+}
+
+#[test]
+fn breakpoint_is_armed_while_any_consumer_is_enabled() {
+    let _guard = setup("/test/consumers_enable");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    load_text(&code);
+    let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let (handler, post) = logging_consumer(&log, "first");
+    let first = uprobe_register("/test/consumers_enable".into(), TEXT, handler, post, ProbeType::Insn).unwrap();
+    first.disable().unwrap();
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    // the first enabled consumer puts the breakpoint back
+    let (handler, post) = logging_consumer(&log, "second");
+    let second = uprobe_register("/test/consumers_enable".into(), TEXT, handler, post, ProbeType::Insn).unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+
+    hit_insn_probe();
+    assert_eq!(*log.lock().unwrap(), [("second", "pre"), ("second", "post")]);
+
+    first.enable().unwrap();
+    second.disable().unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    first.disable().unwrap();
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    second.enable().unwrap();
+    // removing a disabled consumer leaves the breakpoint alone
+    first.unregister().unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    second.disable().unwrap();
+    uprobe_unregister("/test/consumers_enable".into(), TEXT).unwrap();
+    assert_eq!(OS.xol_pages(), 0);
+    assert!(!second.is_armed());
+}
+
+#[test]
+fn consumers_must_agree_on_the_probe_type() {
+    let _guard = setup("/test/mismatch");
+    load_text(&u32_bytes(&[asm::addi(asm::SP, asm::SP, -16)]));
+
+    uprobe_register("/test/mismatch".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    let result = uprobe_register("/test/mismatch".into(), TEXT, handler(), None, ProbeType::SyncFunc);
+    assert_eq!(result.unwrap_err(), UprobeError::ProbeTypeMismatch);
+}
+
+#[test]
+fn unregister_restores_instruction_and_frees_pages() {
+    let _guard = setup("/test/unregister");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1)]);
+    load_text(&code);
+
+    uprobe_register("/test/unregister".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    assert!(OS.xol_pages() > 0);
+    uprobe_unregister("/test/unregister".into(), TEXT).unwrap();
+    assert_eq!(OS.read(TEXT, 4), code);
+    assert_eq!(OS.xol_pages(), 0);
+    assert_eq!(uprobe_unregister("/test/unregister".into(), TEXT), Err(UprobeError::UnknownPath));
+}
+
+#[test]
+fn probes_of_other_executables_are_armed_at_exec() {
+    let _guard = setup("/test/loader");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1)]);
+    load_text(&code);
+
+    uprobe_register("/test/target".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    assert_eq!(OS.read(TEXT, 4), code);
+
+    OS.set_exec_path("/test/target");
+    uprobes_init();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+}
+
+#[test]
+fn disabled_probe_keeps_its_slot() {
+    let _guard = setup("/test/disable");
+    reset_hits();
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]);
+    load_text(&code);
+
+    let probe = uprobe_register("/test/disable".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    assert_eq!(probe.path(), "/test/disable");
+    assert!(probe.is_armed());
+    let pages = OS.xol_pages();
+
+    probe.disable().unwrap();
+    assert!(!probe.is_armed());
+    assert_eq!(OS.read(TEXT, 4), code[..4]);
+    assert_eq!(OS.xol_pages(), pages);
+    // a thread that trapped before the breakpoint was removed just runs the instruction again
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(cx.sepc, TEXT);
+    assert_eq!(HITS.load(Ordering::SeqCst), 0);
+    probe.disable().unwrap();
+
+    probe.enable().unwrap();
+    assert!(probe.is_armed());
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    assert_eq!(HITS.load(Ordering::SeqCst), 1);
+    assert_ne!(cx.sepc, TEXT);
+    let mut cx = trap_context(cx.sepc + 4);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+
+    probe.clone().unregister().unwrap();
+    assert!(!probe.is_armed());
+    assert_eq!(probe.enable(), Err(UprobeError::UnknownPath));
+    assert_eq!(OS.xol_pages(), 0);
+}
+
+#[test]
+fn disabled_probe_is_not_armed_at_exec() {
+    let _guard = setup("/test/disable_loader");
+    let code = u32_bytes(&[asm::addi(asm::A0, asm::A0, 1)]);
+    load_text(&code);
+
+    let probe = uprobe_register("/test/disable_target".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    probe.disable().unwrap();
+    OS.set_exec_path("/test/disable_target");
+    uprobes_init();
+    assert_eq!(OS.read(TEXT, 4), code);
+    assert!(OS.xol_pages() > 0);
+
+    probe.enable().unwrap();
+    assert_eq!(OS.read(TEXT, 4), [0x02, 0x90, 0x02, 0x90]);
+    probe.unregister().unwrap();
+}
+
+#[test]
+fn stale_handle_does_not_touch_a_new_registration() {
+    let _guard = setup("/test/stale");
+    load_text(&u32_bytes(&[asm::NOP, asm::NOP]));
+
+    let old = uprobe_register("/test/stale".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    uprobe_register("/test/stale".into(), TEXT + 4, handler(), None, ProbeType::Insn).unwrap();
+    uprobe_unregister("/test/stale".into(), TEXT).unwrap();
+    let new = uprobe_register("/test/stale".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    assert_ne!(old, new);
+
+    assert!(!old.is_armed());
+    assert_eq!(old.disable(), Err(UprobeError::NotRegistered));
+    assert_eq!(old.unregister(), Err(UprobeError::NotRegistered));
+    assert!(new.is_armed());
+    new.unregister().unwrap();
+}
+
+#[test]
+fn trap_in_process_without_probes_is_not_ours() {
+    let _guard = setup("/test/no_probes");
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    assert_eq!(cx.sepc, TEXT);
+}
+
+#[test]
+fn breakpoint_of_the_program_is_not_ours() {
+    let _guard = setup("/test/own_ebreak");
+    reset_hits();
+    let mut code = u32_bytes(&[asm::NOP, asm::EBREAK]);
+    code.extend_from_slice(&C_EBREAK);
+    load_text(&code);
+    uprobe_register("/test/own_ebreak".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+
+    for addr in [TEXT + 4, TEXT + 8] {
+        let mut cx = trap_context(addr);
+        cx.x[10] = 1;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+        assert_eq!(cx.sepc, addr);
+        assert_eq!(cx.x[10], 1);
+    }
+    assert_eq!(HITS.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn breakpoint_removed_after_the_trap_is_still_ours() {
+    let _guard = setup("/test/late_trap");
+    reset_hits();
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]));
+    uprobe_register("/test/late_trap".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    // another hart unregisters between the trap and its handling
+    uprobe_unregister("/test/late_trap".into(), TEXT).unwrap();
+
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+    // the thread runs the restored instruction
+    assert_eq!(cx.sepc, TEXT);
+    assert_eq!(HITS.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn probe_removed_while_its_handlers_run_leaves_nothing_in_the_area() {
+    let _guard = setup("/test/removed_in_handler");
+    let code = u32_bytes(&[asm::addi(asm::SP, asm::SP, -16), asm::RET]);
+    load_text(&code);
+    let returns = Arc::new(AtomicUsize::new(0));
+
+    for (probe_type, post) in [(ProbeType::Insn, None), (ProbeType::SyncFunc, Some(post_handler(&returns)))] {
+        // as another thread removing the last probe of the process in the meantime
+        let remove: UprobeHandler = Arc::new(Mutex::new(|_cx: &mut TrapContext, addr: usize| {
+            uprobe_unregister("/test/removed_in_handler".into(), addr).unwrap();
+        }));
+        uprobe_register("/test/removed_in_handler".into(), TEXT, remove, post, probe_type).unwrap();
+        assert_ne!(OS.xol_pages(), 0);
+
+        let mut cx = trap_context(TEXT);
+        cx.x[1] = 0x2_0000;
+        assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::Handled);
+        // the restored instruction runs in place, neither stepped nor returning through the unmapped area
+        assert_eq!((cx.sepc, cx.x[1]), (TEXT, 0x2_0000));
+        assert_eq!(OS.read(TEXT, 8), code);
+        assert_eq!(OS.xol_pages(), 0);
+    }
+    assert_eq!(returns.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn breakpoint_already_in_place_is_not_probed() {
+    let _guard = setup("/test/debugger");
+    let mut code = u32_bytes(&[asm::EBREAK]);
+    code.extend_from_slice(&C_EBREAK);
+    load_text(&code);
+
+    for addr in [TEXT, TEXT + 4] {
+        let result = uprobe_register("/test/debugger".into(), addr, handler(), None, ProbeType::Insn);
+        assert_eq!(result.unwrap_err(), UprobeError::ExistingBreakpoint);
+    }
+    assert_eq!(OS.read(TEXT, 6), code);
+}
+
+#[test]
+fn breakpoint_placed_on_a_disabled_probe_is_not_ours() {
+    let _guard = setup("/test/debugger_disabled");
+    reset_hits();
+    load_text(&u32_bytes(&[asm::addi(asm::A0, asm::A0, 1), asm::NOP]));
+    let probe = uprobe_register("/test/debugger_disabled".into(), TEXT, handler(), None, ProbeType::Insn).unwrap();
+    probe.disable().unwrap();
+    // a debugger sets its own breakpoint on the restored instruction
+    OS.write(TEXT, &u32_bytes(&[asm::EBREAK]));
+
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    assert_eq!(cx.sepc, TEXT);
+    assert_eq!(HITS.load(Ordering::SeqCst), 0);
+
+    // neither enabling nor disabling again touches it
+    probe.enable().unwrap();
+    assert_eq!(OS.read(TEXT, 4), u32_bytes(&[asm::EBREAK]));
+    let mut cx = trap_context(TEXT);
+    assert_eq!(uprobes_trap_handler(&mut cx), UprobeTrapResult::NotOurs);
+    probe.disable().unwrap();
+    probe.unregister().unwrap();
+    assert_eq!(OS.read(TEXT, 4), u32_bytes(&[asm::EBREAK]));
+    assert_eq!(HITS.load(Ordering::SeqCst), 0);
+}